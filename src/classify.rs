@@ -0,0 +1,558 @@
+//! Read pair classification, independent of any output
+
+use crate::digest;
+use crate::fragments::{get_read_location, FragmentIndex};
+use crate::quality::{check_alignment_quality, get_multimap_evidence, MultimapEvidence, MultimapPolicy, QualityFilter, QualityReason};
+use crate::reads::*;
+use bed_utils::bed::{BEDLike, BED};
+use noodles_bam as bam;
+use noodles_sam as sam;
+use std::error::Error;
+use std::fmt;
+use std::ptr;
+
+/// Orientation of a pair, from the strands of its ordered reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Orientation {
+    /// ->->
+    FF,
+    /// <-<-
+    RR,
+    /// -><-
+    FR,
+    /// <-->
+    RF,
+}
+
+impl fmt::Display for Orientation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            Orientation::FF => "FF",
+            Orientation::RR => "RR",
+            Orientation::FR => "FR",
+            Orientation::RF => "RF",
+        };
+        f.write_str(code)
+    }
+}
+
+/// Why a pair was filtered (FILT)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FilterReason {
+    InsertTooSmall,
+    InsertTooLarge,
+    /// Intrachromosomal pair closer than the minimum cis distance
+    CisTooClose,
+}
+
+impl FilterReason {
+    pub const ALL: [FilterReason; 3] = [
+        FilterReason::InsertTooSmall,
+        FilterReason::InsertTooLarge,
+        FilterReason::CisTooClose,
+    ];
+}
+
+impl fmt::Display for FilterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            FilterReason::InsertTooSmall => "insert_too_small",
+            FilterReason::InsertTooLarge => "insert_too_large",
+            FilterReason::CisTooClose => "cis_too_close",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Why a pair could not be classified (DUMP)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DumpReason {
+    /// A read overlaps no restriction fragment
+    NoFragment,
+    /// A read overlaps several restriction fragments
+    AmbiguousFragment,
+    /// Both reads on the same fragment and strand, neither dangling end nor self circle
+    SameFragmentSameStrand,
+    /// A mapped read without reference sequence in the header
+    NoReference,
+}
+
+impl DumpReason {
+    pub const ALL: [DumpReason; 4] = [
+        DumpReason::NoFragment,
+        DumpReason::AmbiguousFragment,
+        DumpReason::SameFragmentSameStrand,
+        DumpReason::NoReference,
+    ];
+}
+
+impl fmt::Display for DumpReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            DumpReason::NoFragment => "no_fragment",
+            DumpReason::AmbiguousFragment => "ambiguous_fragment",
+            DumpReason::SameFragmentSameStrand => "same_fragment_same_strand",
+            DumpReason::NoReference => "no_reference",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Class of a read pair, filtered, dumped and low quality pairs carrying their reason
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum InteractionType {
+    Valid,
+    DanglingEnd,
+    Religation,
+    SelfCircle,
+    SingleEnd,
+    Filtered(FilterReason),
+    Dumped(DumpReason),
+    LowQuality(QualityReason),
+}
+
+impl InteractionType {
+    /// HiC-Pro code of the class: VI, DE, RE, SC, SI, FILT, DUMP or LOWQ
+    pub fn code(&self) -> &'static str {
+        match self {
+            InteractionType::Valid => "VI",
+            InteractionType::DanglingEnd => "DE",
+            InteractionType::Religation => "RE",
+            InteractionType::SelfCircle => "SC",
+            InteractionType::SingleEnd => "SI",
+            InteractionType::Filtered(_) => "FILT",
+            InteractionType::Dumped(_) => "DUMP",
+            InteractionType::LowQuality(_) => "LOWQ",
+        }
+    }
+}
+
+impl fmt::Display for InteractionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Classification settings, i.e. the fragment mode and the pair filters
+#[derive(Debug, Clone, Default)]
+pub struct ClassifyOptions {
+    /// Classify from distance and orientation only, without restriction fragments
+    pub enzyme_free: bool,
+    pub min_insert_size: Option<u64>,
+    pub max_insert_size: Option<u64>,
+    pub min_cis_dist: Option<u64>,
+    /// Enzyme-free minimum distances per orientation, defaulting to min_cis_dist
+    pub min_dist_fr: Option<u64>,
+    pub min_dist_rf: Option<u64>,
+    pub min_dist_ff: Option<u64>,
+    pub min_dist_rr: Option<u64>,
+    /// Per-mate alignment filters, applied before classification
+    pub quality: QualityFilter,
+}
+
+/// Whether two fragments of the same chromosome are adjacent
+pub fn are_contiguous_fragments(frag1:BED<6>, frag2:BED<6>, chr1:usize, chr2:usize) -> bool{
+    if chr1 != chr2{
+        return false
+    } 
+    let frag1_touch_frag2 = frag1.end() == frag2.start();
+    let frag2_touch_frag1 = frag1.start() == frag2.end();
+    frag2_touch_frag1 || frag1_touch_frag2
+}
+
+/// Whether two fragments have the same coordinates
+pub fn are_same_fragment(frag1: &BED<6>, frag2: &BED<6>) -> bool {
+    frag1.chrom() == frag2.chrom() && 
+    frag1.start() == frag2.start() && 
+    frag1.end() == frag2.end()
+}
+
+/// Whether the reads of a pair are on adjacent restriction fragments, false if one of them is unmapped
+pub fn is_religation(read1: &bam::Record, read2: &bam::Record,frag1:BED<6>, frag2:BED<6>)-> bool{
+    let tid1_opt = read1.reference_sequence_id().transpose().ok().flatten();
+    let tid2_opt = read2.reference_sequence_id().transpose().ok().flatten();
+    match (tid1_opt, tid2_opt) {
+        (Some(tid1), Some(tid2)) => are_contiguous_fragments(frag1, frag2, tid1, tid2),
+        _ => false,
+    }
+}
+
+/// Whether the ordered reads of a pair are oriented outward (<- ->)
+pub fn is_self_circle(read1: &bam::Record, read2: &bam::Record) -> bool{
+    if let Some((r1,r2)) = get_ordered_reads(read1, read2) {
+        (get_read_strand(r1) == "-") && (get_read_strand(r2) == "+")
+    } else{
+        false
+    }
+}
+
+/**
+    Both reads are expected to be on the same restriction fragments
+    Check the orientation of reads -><-
+
+    read1 : [bam::Record]
+    read2 : [bam::Record]
+ */
+pub fn is_dangling_end(read1: &bam::Record, read2: &bam::Record) -> bool{
+    if let Some((r1, r2)) = get_ordered_reads(read1, read2) {
+        get_read_strand(r1) == "+" && get_read_strand(r2) == "-"
+    } else {
+        false
+    }
+}
+
+/**
+    Both reads are expected to be on the different restriction fragments
+    Check the orientation of reads ->-> / <-<- / -><- / <-->
+
+    read1 : [bam::Record]
+    read2 : [bam::Record]
+ */
+pub fn get_valid_orientation(read1: &bam::Record, read2: &bam::Record) -> Option<Orientation> {
+    let (r1, r2) = get_ordered_reads(read1, read2)?;
+    let orientation = match (r1.flags().is_reverse_complemented(), r2.flags().is_reverse_complemented()) {
+        (false, false) => Orientation::FF,
+        (true, true) => Orientation::RR,
+        (false, true) => Orientation::FR,
+        (true, false) => Orientation::RF,
+    };
+    Some(orientation)
+}
+
+/// Insert size of a pair from the read positions and their restriction fragments, as HiC-Pro computes it
+pub fn get_pe_fragment_size(read1: &bam::Record, read2: &bam::Record, 
+    res_frag1: Option<BED<6>>, res_frag2: Option<BED<6>>,
+    interaction_type: InteractionType) -> Option<u64>{
+ // 1. Get ordered reads. If this is None, the chain stops and returns None.
+    get_ordered_reads(read1, read2).and_then(|(r1, r2)| {
+        
+        // 2. Pair up fragments with the *ordered* reads.
+        //    (This now assigns references, which is cheap and safe)
+        let (rfrag1, rfrag2) = if ptr::eq(r1, read2) { // Check if r1 is the original read2
+            (res_frag2, res_frag1) 
+        } else {
+            (res_frag1, res_frag2)
+        };
+        
+        // Check if we have valid fragments
+        let (rfrag1, rfrag2) = match (rfrag1, rfrag2) {
+            (Some(f1), Some(f2)) => (f1, f2),
+            _ => return None,
+        };
+        let r1pos_opt = get_read_pos(r1, "start");
+        let r2pos_opt = get_read_pos(r2, "start");
+
+        r1pos_opt.zip(r2pos_opt).map(|(r1pos_usize, r2pos_usize)| {
+            
+            // These variables are defined *inside* this closure
+            let r1pos = r1pos_usize as u64;
+            let r2pos = r2pos_usize as u64;
+
+            // 4. Calculate size. Use `saturating_sub` to PREVENT panics.
+            match interaction_type {
+            InteractionType::DanglingEnd | InteractionType::Religation => {
+                // r2 is the rightmost read, so r2pos >= r1pos
+                r2pos.saturating_sub(r1pos)
+            }
+            InteractionType::SelfCircle => {
+                let d1 = r1pos.saturating_sub(rfrag1.start() + 1);
+                let d2 = rfrag2.end().saturating_sub(r2pos + 1);
+                d1 + d2
+            }
+            InteractionType::Valid => {
+                let dr1 = if get_read_strand(r1) == "+" {
+                    rfrag1.end().saturating_sub(r1pos + 1)
+                } else {
+                    r1pos.saturating_sub(rfrag1.start()+1)
+                };
+                
+                let dr2 = if get_read_strand(r2) == "+" {
+                    rfrag2.end().saturating_sub(r2pos+1)
+                } else {
+                    r2pos.saturating_sub(rfrag2.start()+1)
+                };
+                dr1 + dr2
+            }
+            _ => 0, // Safe default
+            }
+        })
+    })
+}
+
+/// HiC-Pro classification of a pair from its restriction fragments: VI, DE, SC, RE, SI or DUMP
+pub(crate) fn get_interaction_type(read1: &bam::Record, res_frag1 : Option<BED<6>>,
+    read2: &bam::Record, res_frag2 : Option<BED<6>>)-> InteractionType{
+        match (read1.flags().is_unmapped(), read2.flags().is_unmapped(),&res_frag1, &res_frag2) {
+            (false, false, Some(rf1), Some(rf2)) =>{
+                if are_same_fragment(rf1, rf2) {
+                    if is_self_circle(read1, read2){
+                        InteractionType::SelfCircle
+                    } else if is_dangling_end(read1, read2){
+                        InteractionType::DanglingEnd
+                    } else{
+                        InteractionType::Dumped(DumpReason::SameFragmentSameStrand)
+                    }
+                } else {
+                    if is_religation(read1, read2, rf1.clone(), rf2.clone()) {
+                        InteractionType::Religation
+                    } else {
+                        // This is the valid interaction case
+                        InteractionType::Valid
+                    }
+                }
+            },
+            (true,_,_,_) | (_,true,_,_) =>{
+                InteractionType::SingleEnd
+            },
+            _ => InteractionType::Dumped(DumpReason::NoFragment),
+        }
+    }
+
+
+
+/**
+    Classify a pair without restriction fragments (Micro-C, DNase Hi-C).
+    Intrachromosomal pairs closer than the minimum distance of their orientation
+    are dangling ends (FR), self circles (RF) or filtered (FF/RR); the thresholds
+    default to min_cis_dist.
+
+    read1 : [bam::Record]
+    read2 : [bam::Record]
+ */
+pub fn get_enzyme_free_interaction_type(read1: &bam::Record, read2: &bam::Record, options: &ClassifyOptions) -> InteractionType {
+    if read1.flags().is_unmapped() || read2.flags().is_unmapped() {
+        return InteractionType::SingleEnd;
+    }
+    let (Some(intra_chrom), Some(orientation)) = (is_intra_chrom(read1, read2), get_valid_orientation(read1, read2)) else {
+        return InteractionType::Dumped(DumpReason::NoReference);
+    };
+    if !intra_chrom {
+        return InteractionType::Valid;
+    }
+    let (min_dist, short_type) = match orientation {
+        Orientation::FR => (options.min_dist_fr, InteractionType::DanglingEnd),
+        Orientation::RF => (options.min_dist_rf, InteractionType::SelfCircle),
+        Orientation::FF => (options.min_dist_ff, InteractionType::Filtered(FilterReason::CisTooClose)),
+        Orientation::RR => (options.min_dist_rr, InteractionType::Filtered(FilterReason::CisTooClose)),
+    };
+    let min_dist = min_dist.or(options.min_cis_dist).unwrap_or(0);
+    match get_cis_distance(read1, read2) {
+        Some(cis_dist) if (cis_dist as u64) < min_dist => short_type,
+        _ => InteractionType::Valid,
+    }
+}
+
+/**
+    Ligation junction of a valid pair, i.e. the enzymes of the fragment ends
+    each read is oriented to. Only available for enzyme-annotated fragments.
+ */
+pub fn get_ligation_junction(read1: &bam::Record, frag1: &BED<6>, read2: &bam::Record, frag2: &BED<6>) -> Option<String> {
+    let end_enzyme = |read: &bam::Record, frag: &BED<6>| {
+        let (left, right) = frag.name().and_then(digest::parse_fragment_enzymes)?;
+        let enzyme = if get_read_strand(read) == "+" { right } else { left };
+        (enzyme != digest::NO_ENZYME).then(|| enzyme.to_string())
+    };
+    let mut ends = [end_enzyme(read1, frag1)?, end_enzyme(read2, frag2)?];
+    ends.sort();
+    Some(ends.join("-"))
+}
+
+/**
+    Classify a read pair and apply the insert size and cis distance filters.
+    Return the final interaction type and the insert size.
+ */
+pub fn get_filtered_interaction_type(
+    r1: &bam::Record,
+    r1_resfrag: Option<&BED<6>>,
+    r2: &bam::Record,
+    r2_resfrag: Option<&BED<6>>,
+    options: &ClassifyOptions,
+) -> (InteractionType, Option<u64>) {
+    let interaction_type = if options.enzyme_free {
+        get_enzyme_free_interaction_type(r1, r2, options)
+    } else {
+        get_interaction_type(r1, r1_resfrag.cloned(), r2, r2_resfrag.cloned())
+    };
+    
+    let dist = get_pe_fragment_size(r1, r2, 
+        r1_resfrag.cloned(),
+        r2_resfrag.cloned(),
+        interaction_type
+    );
+    
+    let cdist = get_cis_distance(r1, r2);
+    
+    // Apply filters
+    let mut final_interaction_type = interaction_type;
+    
+    // Check insert size criteria
+    if let Some(distance) = dist {
+        if let Some(min_size) = options.min_insert_size {
+            if distance < min_size {
+                final_interaction_type = InteractionType::Filtered(FilterReason::InsertTooSmall);
+            }
+        }
+        if let Some(max_size) = options.max_insert_size {
+            if distance > max_size {
+                final_interaction_type = InteractionType::Filtered(FilterReason::InsertTooLarge);
+            }
+        }
+    }
+    
+    // Check distance criteria for valid interactions, already applied per orientation in enzyme-free mode
+    if final_interaction_type == InteractionType::Valid && !options.enzyme_free {
+        if let Some(min_dist) = options.min_cis_dist {
+            if let Some(cis_dist) = cdist {
+                if (cis_dist as u64) < min_dist {
+                    final_interaction_type = InteractionType::Filtered(FilterReason::CisTooClose);
+                }
+            }
+        }
+    }
+
+    (final_interaction_type, dist)
+}
+
+/// A read pair with its locations and classification, computed independently of the outputs
+pub struct ClassifiedPair {
+    pub r1: bam::Record,
+    pub r1_chrom: Option<String>,
+    pub r1_resfrag: Option<BED<6>>,
+    /// Why the mate is considered multi-mapped, if it is
+    pub r1_multimap: Option<MultimapEvidence>,
+    pub r2: bam::Record,
+    pub r2_chrom: Option<String>,
+    pub r2_resfrag: Option<BED<6>>,
+    pub r2_multimap: Option<MultimapEvidence>,
+    pub interaction_type: InteractionType,
+    pub dist: Option<u64>,
+    /// Split alignments not selected for the mates and secondary alignments, written to the BAM output with the pair
+    pub other_records: Vec<bam::Record>,
+}
+
+/// Outcome of the classification of the records sharing a query name
+pub enum ClassifiedGroup {
+    Pair { pair: Box<ClassifiedPair>, chimeric: bool },
+    Orphan,
+    Empty,
+}
+
+/**
+    Classify the records sharing a query name. Each mate is represented by its
+    5'-most alignment among the primary and supplementary records, so that
+    chimeric reads are rescued, the other records being kept with the pair.
+    Pairs with a mate failing the quality filters
+    (or multi-mapped, with the discard policy) are not classified. Groups
+    missing one of the mates are orphans. Nothing is written here, so that
+    groups can be classified on worker threads.
+ */
+pub fn classify_read_group(
+    group: Vec<bam::Record>,
+    headers: &sam::Header,
+    bed_ladder: &FragmentIndex,
+    options: &ClassifyOptions,
+) -> Result<ClassifiedGroup, Box<dyn Error>> {
+    let mut r1_alignments: Vec<bam::Record> = Vec::new();
+    let mut r2_alignments: Vec<bam::Record> = Vec::new();
+    let mut secondary_records: Vec<bam::Record> = Vec::new();
+    for record in group {
+        let flags = record.flags();
+        if flags.is_secondary() {
+            secondary_records.push(record);
+        } else if flags.is_first_segment() {
+            r1_alignments.push(record);
+        } else if flags.is_last_segment() {
+            r2_alignments.push(record);
+        }
+    }
+    
+    let is_chimeric = r1_alignments.iter().chain(r2_alignments.iter())
+        .any(|read| read.flags().is_supplementary());
+    let r1 = select_5prime_alignment(&mut r1_alignments);
+    let r2 = select_5prime_alignment(&mut r2_alignments);
+    
+    match (r1, r2) {
+        (Some(r1), Some(r2)) => {
+            let (r1_chrom, r1_lookup) = get_read_location(&r1, headers, bed_ladder, options.enzyme_free)?;
+            let (r2_chrom, r2_lookup) = get_read_location(&r2, headers, bed_ladder, options.enzyme_free)?;
+            let missing = r1_lookup.as_ref().err().or(r2_lookup.as_ref().err()).copied();
+            let (r1_resfrag, r2_resfrag) = (r1_lookup.ok().flatten(), r2_lookup.ok().flatten());
+            let (r1_multimap, r2_multimap) = (get_multimap_evidence(&r1), get_multimap_evidence(&r2));
+            let multimapped = options.quality.multimap_policy == MultimapPolicy::Discard
+                && (r1_multimap.is_some() || r2_multimap.is_some());
+            let low_quality = check_alignment_quality(&r1, &options.quality)
+                .or_else(|| check_alignment_quality(&r2, &options.quality))
+                .or(multimapped.then_some(QualityReason::Multimapped));
+            let (interaction_type, dist) = match (low_quality, missing) {
+                (Some(reason), _) => (InteractionType::LowQuality(reason), None),
+                // Both mates mapped but without restriction fragment
+                (None, Some(reason)) if !r1.flags().is_unmapped() && !r2.flags().is_unmapped() => {
+                    (InteractionType::Dumped(reason), None)
+                }
+                _ => get_filtered_interaction_type(&r1, r1_resfrag.as_ref(), &r2, r2_resfrag.as_ref(), options),
+            };
+            let other_records = r1_alignments.into_iter().chain(r2_alignments).chain(secondary_records).collect();
+            let pair = Box::new(ClassifiedPair {
+                r1, r1_chrom, r1_resfrag, r1_multimap, r2, r2_chrom, r2_resfrag, r2_multimap, interaction_type, dist,
+                other_records,
+            });
+            Ok(ClassifiedGroup::Pair { pair, chimeric: is_chimeric })
+        }
+        (Some(_), None) | (None, Some(_)) => Ok(ClassifiedGroup::Orphan),
+        (None, None) => Ok(ClassifiedGroup::Empty),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::tests::{encode_records, get_header};
+
+    #[test]
+    fn test_enzyme_free_min_distances() {
+        let options = ClassifyOptions {
+            enzyme_free: true,
+            min_cis_dist: Some(1000),
+            min_dist_fr: Some(500),
+            ..Default::default()
+        };
+        // FR and FF pairs 709 bp apart, FR pair 209 bp apart
+        let reads = encode_records(&[
+            "fr\t97\tchr1\t1001\t60\t10M\tchr1\t1701\t0\tACGTACGTAC\t*",
+            "fr\t145\tchr1\t1701\t60\t10M\tchr1\t1001\t0\tACGTACGTAC\t*",
+            "ff\t65\tchr1\t1001\t60\t10M\tchr1\t1710\t0\tACGTACGTAC\t*",
+            "ff\t129\tchr1\t1710\t60\t10M\tchr1\t1001\t0\tACGTACGTAC\t*",
+            "de\t97\tchr1\t1001\t60\t10M\tchr1\t1201\t0\tACGTACGTAC\t*",
+            "de\t145\tchr1\t1201\t60\t10M\tchr1\t1001\t0\tACGTACGTAC\t*",
+        ]);
+        let classify = |r1: &bam::Record, r2: &bam::Record| {
+            get_filtered_interaction_type(r1, None, r2, None, &options).0
+        };
+        // The orientation threshold replaces min_cis_dist, which only applies by default
+        assert_eq!(classify(&reads[0], &reads[1]), InteractionType::Valid);
+        assert_eq!(classify(&reads[2], &reads[3]), InteractionType::Filtered(FilterReason::CisTooClose));
+        assert_eq!(classify(&reads[4], &reads[5]), InteractionType::DanglingEnd);
+    }
+
+    #[test]
+    fn test_classify_chimeric_group() {
+        let options = ClassifyOptions { enzyme_free: true, ..Default::default() };
+        // Chimeric R1 whose split part covers the 5' end, with a secondary alignment
+        let group = encode_records(&[
+            "read1\t65\tchr1\t1001\t60\t5S5M\tchr1\t5001\t0\tACGTACGTAC\t*\tSA:Z:chr2,301,+,5M5S,60,0;",
+            "read1\t321\tchr1\t8001\t0\t10M\tchr1\t5001\t0\t*\t*",
+            "read1\t2113\tchr2\t301\t60\t5M5H\tchr1\t5001\t0\tACGTA\t*\tSA:Z:chr1,1001,+,5S5M,60,0;",
+            "read1\t129\tchr1\t5001\t60\t10M\tchr1\t1001\t0\tACGTACGTAC\t*",
+        ]);
+        let ClassifiedGroup::Pair { pair, chimeric } = classify_read_group(group, &get_header(), &FragmentIndex::new(), &options).unwrap() else {
+            panic!("read1 should be classified as a pair");
+        };
+        assert!(chimeric);
+        assert!(pair.r1.flags().is_supplementary());
+        assert_eq!(pair.r1_chrom.as_deref(), Some("chr2"));
+        assert_eq!(pair.interaction_type, InteractionType::Valid);
+        // The primary and secondary records of R1 stay with the pair, for the BAM output
+        let others: Vec<_> = pair.other_records.iter().map(|record| u16::from(record.flags())).collect();
+        assert_eq!(others, [65, 321]);
+    }
+}
@@ -0,0 +1,115 @@
+//! HDF5 .cool/.mcool writers (cooler schema v3)
+
+use crate::matrix::ContactMatrix;
+use hdf5::types::VarLenUnicode;
+use hdf5::{Group, H5Type};
+use std::error::Error;
+use std::path::Path;
+
+fn write_str_attr(group: &Group, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    let value: VarLenUnicode = value.parse()?;
+    group.new_attr::<VarLenUnicode>().shape(()).create(name)?.write_scalar(&value)?;
+    Ok(())
+}
+
+fn write_int_attr(group: &Group, name: &str, value: i64) -> Result<(), Box<dyn Error>> {
+    group.new_attr::<i64>().shape(()).create(name)?.write_scalar(&value)?;
+    Ok(())
+}
+
+fn write_column<T: H5Type>(group: &Group, name: &str, data: &[T]) -> Result<(), Box<dyn Error>> {
+    group.new_dataset_builder().with_data(data).create(name)?;
+    Ok(())
+}
+
+/**
+    Write a contact matrix into a group following the cooler schema v3
+    (chroms, bins, pixels and indexes groups, symmetric-upper storage).
+    Lengths, coordinates and counts are stored as 64-bit integers, as cooler
+    accepts, so that chromosomes over 2 Gb and large counts are not wrapped.
+ */
+fn write_cooler(group: &Group, matrix: &ContactMatrix) -> Result<(), Box<dyn Error>> {
+    let n_bins = matrix.n_bins();
+    let pixels = matrix.pixels();
+
+    let chroms = group.create_group("chroms")?;
+    let names = matrix.chroms.iter()
+        .map(|(name, _)| name.parse::<VarLenUnicode>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let lengths: Vec<i64> = matrix.chroms.iter().map(|(_, len)| *len as i64).collect();
+    write_column(&chroms, "name", &names)?;
+    write_column(&chroms, "length", &lengths)?;
+
+    let bins = group.create_group("bins")?;
+    let (mut bin_chrom, mut bin_start, mut bin_end) = (Vec::new(), Vec::new(), Vec::new());
+    for (tid, start, end) in matrix.bins() {
+        bin_chrom.push(i32::try_from(tid)?);
+        bin_start.push(start as i64);
+        bin_end.push(end as i64);
+    }
+    write_column(&bins, "chrom", &bin_chrom)?;
+    write_column(&bins, "start", &bin_start)?;
+    write_column(&bins, "end", &bin_end)?;
+
+    let pixels_group = group.create_group("pixels")?;
+    let bin1_id: Vec<i64> = pixels.iter().map(|&(bin1, _, _)| bin1 as i64).collect();
+    let bin2_id: Vec<i64> = pixels.iter().map(|&(_, bin2, _)| bin2 as i64).collect();
+    let count: Vec<i64> = pixels.iter().map(|&(_, _, count)| count as i64).collect();
+    write_column(&pixels_group, "bin1_id", &bin1_id)?;
+    write_column(&pixels_group, "bin2_id", &bin2_id)?;
+    write_column(&pixels_group, "count", &count)?;
+
+    // chrom_offset: first bin of each chromosome, bin1_offset: first pixel of each bin
+    let indexes = group.create_group("indexes")?;
+    let chrom_offset: Vec<i64> = matrix.chrom_offsets().iter()
+        .map(|&offset| offset as i64)
+        .chain(std::iter::once(n_bins as i64))
+        .collect();
+    let mut bin1_offset = Vec::with_capacity(n_bins as usize + 1);
+    let mut pixel = 0;
+    for bin in 0..=n_bins {
+        while pixel < pixels.len() && pixels[pixel].0 < bin {
+            pixel += 1;
+        }
+        bin1_offset.push(pixel as i64);
+    }
+    write_column(&indexes, "chrom_offset", &chrom_offset)?;
+    write_column(&indexes, "bin1_offset", &bin1_offset)?;
+
+    write_str_attr(group, "format", "HDF5::Cooler")?;
+    write_int_attr(group, "format-version", 3)?;
+    write_str_attr(group, "bin-type", "fixed")?;
+    write_int_attr(group, "bin-size", matrix.resolution as i64)?;
+    write_str_attr(group, "storage-mode", "symmetric-upper")?;
+    write_int_attr(group, "nbins", n_bins as i64)?;
+    write_int_attr(group, "nchroms", matrix.chroms.len() as i64)?;
+    write_int_attr(group, "nnz", pixels.len() as i64)?;
+    write_str_attr(group, "generated-by", &format!("hic2frag-{}", env!("CARGO_PKG_VERSION")))?;
+    write_str_attr(group, "metadata", "{}")?;
+    Ok(())
+}
+
+/// Write a single resolution .cool file
+pub fn write_cool(path: &Path, matrix: &ContactMatrix) -> Result<(), Box<dyn Error>> {
+    let file = hdf5::File::create(path)?;
+    write_cooler(&file, matrix)?;
+    Ok(())
+}
+
+/**
+    Write a multi-resolution .mcool file, each zoom level being stored as a
+    cooler under /resolutions/<bin size>
+
+    levels : contact matrices of the zoom pyramid, from the finest resolution
+ */
+pub fn write_mcool(path: &Path, levels: &[&ContactMatrix]) -> Result<(), Box<dyn Error>> {
+    let file = hdf5::File::create(path)?;
+    write_str_attr(&file, "format", "HDF5::MCOOL")?;
+    write_int_attr(&file, "format-version", 2)?;
+    let resolutions = file.create_group("resolutions")?;
+    for matrix in levels {
+        let group = resolutions.create_group(&matrix.resolution.to_string())?;
+        write_cooler(&group, matrix)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Duplicate detection of valid pairs
+
+use std::collections::HashMap;
+
+// (chrom1, reverse1, chrom2, reverse2, bucket1, bucket2)
+type PairBucket = (usize, bool, usize, bool, u64, u64);
+
+/**
+    Streaming PCR/optical duplicate detection on valid pairs, keyed on
+    (chrom1, pos1, strand1, chrom2, pos2, strand2) of the ordered reads.
+    Positions are hashed into buckets of tolerance + 1 bp so that a pair only
+    has to be compared with the pairs of the neighbouring buckets.
+ */
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    tolerance: u64,
+    seen: HashMap<PairBucket, Vec<(u64, u64)>>,
+}
+
+impl DuplicateFilter {
+    /**
+        tolerance : maximum distance (bp) between the positions of two duplicates, on both ends
+     */
+    pub fn new(tolerance: u64) -> Self {
+        DuplicateFilter { tolerance, seen: HashMap::new() }
+    }
+
+    /// Whether an already seen pair lies within the tolerance, the pair being remembered otherwise
+    pub fn is_duplicate(&mut self, tid1: usize, pos1: u64, reverse1: bool,
+        tid2: usize, pos2: u64, reverse2: bool) -> bool {
+        let width = self.tolerance + 1;
+        let (bucket1, bucket2) = (pos1 / width, pos2 / width);
+        for b1 in bucket1.saturating_sub(1)..=bucket1 + 1 {
+            for b2 in bucket2.saturating_sub(1)..=bucket2 + 1 {
+                let found = self.seen.get(&(tid1, reverse1, tid2, reverse2, b1, b2))
+                    .is_some_and(|pairs| pairs.iter().any(|&(p1, p2)| {
+                        p1.abs_diff(pos1) <= self.tolerance && p2.abs_diff(pos2) <= self.tolerance
+                    }));
+                if found {
+                    return true;
+                }
+            }
+        }
+        self.seen.entry((tid1, reverse1, tid2, reverse2, bucket1, bucket2))
+            .or_default()
+            .push((pos1, pos2));
+        false
+    }
+}
+
+/**
+    Estimated number of distinct molecules in the library, as Picard
+    EstimateLibraryComplexity does, solving C/X = 1 - exp(-N/X)
+
+    pairs : number of valid pairs (N)
+    unique_pairs : number of valid pairs without duplicates (C)
+ */
+pub fn estimate_library_size(pairs: u64, unique_pairs: u64) -> Option<u64> {
+    if unique_pairs == 0 || unique_pairs >= pairs {
+        return None;
+    }
+    let (n, c) = (pairs as f64, unique_pairs as f64);
+    let f = |x: f64| c / x - 1.0 + (-n / x).exp();
+
+    let mut lower = 1.0;
+    let mut upper = 100.0;
+    if f(lower * c) < 0.0 {
+        return None;
+    }
+    while f(upper * c) > 0.0 {
+        upper *= 10.0;
+    }
+    for _ in 0..40 {
+        let r = (lower + upper) / 2.0;
+        let u = f(r * c);
+        if u == 0.0 {
+            break;
+        } else if u > 0.0 {
+            lower = r;
+        } else {
+            upper = r;
+        }
+    }
+    Some((c * (lower + upper) / 2.0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_library_size() {
+        // Values of Picard DuplicationMetrics.estimateLibrarySize
+        assert_eq!(estimate_library_size(1000, 900), Some(4660));
+        assert_eq!(estimate_library_size(10_000, 5_000), Some(6275));
+        assert_eq!(estimate_library_size(10_000_000, 8_000_000), Some(21_541_846));
+        assert_eq!(estimate_library_size(100, 99), Some(4966));
+    }
+
+    #[test]
+    fn test_estimate_library_size_undefined() {
+        assert_eq!(estimate_library_size(0, 0), None);
+        assert_eq!(estimate_library_size(100, 0), None);
+        // Without duplicates, the library size is unbounded
+        assert_eq!(estimate_library_size(100, 100), None);
+    }
+
+    #[test]
+    fn test_duplicate_filter() {
+        let mut filter = DuplicateFilter::new(0);
+        assert!(!filter.is_duplicate(0, 100, false, 1, 200, true));
+        assert!(filter.is_duplicate(0, 100, false, 1, 200, true));
+        assert!(!filter.is_duplicate(0, 101, false, 1, 200, true));
+        assert!(!filter.is_duplicate(0, 100, true, 1, 200, true));
+        assert!(!filter.is_duplicate(1, 100, false, 1, 200, true));
+    }
+
+    #[test]
+    fn test_duplicate_filter_tolerance() {
+        let mut filter = DuplicateFilter::new(2);
+        assert!(!filter.is_duplicate(0, 100, false, 0, 500, false));
+        // Within the tolerance on both ends, across bucket boundaries
+        assert!(filter.is_duplicate(0, 102, false, 0, 498, false));
+        assert!(filter.is_duplicate(0, 98, false, 0, 502, false));
+        assert!(!filter.is_duplicate(0, 103, false, 0, 500, false));
+        assert!(!filter.is_duplicate(0, 100, false, 0, 503, false));
+    }
+}
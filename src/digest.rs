@@ -0,0 +1,407 @@
+//! In silico genome digestion into restriction fragments
+
+use bed_utils::bed::{OptionalFields, Score, Strand, BED};
+use flate2::read::MultiGzDecoder;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Cut-site motifs of common Hi-C restriction enzymes, '^' being the cut position on the forward strand
+const RESTRICTION_ENZYMES: &[(&str, &str)] = &[
+    ("DpnII", "^GATC"),
+    ("MboI", "^GATC"),
+    ("Sau3AI", "^GATC"),
+    ("HindIII", "A^AGCTT"),
+    ("BglII", "A^GATCT"),
+    ("NcoI", "C^CATGG"),
+    ("EcoRI", "G^AATTC"),
+    ("HinfI", "G^ANTC"),
+    ("DdeI", "C^TNAG"),
+    ("MseI", "T^TAA"),
+    ("MluCI", "^AATT"),
+    ("NlaIII", "CATG^"),
+    ("AluI", "AG^CT"),
+    ("CviQI", "G^TAC"),
+    ("Csp6I", "G^TAC"),
+];
+
+/// Multi-enzyme Hi-C kits, cutting with all the listed enzymes
+const DIGESTION_KITS: &[(&str, &[&str])] = &[
+    ("Arima", &["DpnII", "HinfI"]),
+    ("Arima2", &["DpnII", "HinfI", "DdeI", "MseI"]),
+];
+
+/// Label of a chromosome end in annotated fragment names
+pub const NO_ENZYME: &str = ".";
+
+/// A restriction site motif with its cut position
+#[derive(Debug, Clone)]
+pub struct CutSite {
+    pub name: String,
+    pub motif: Vec<u8>,
+    pub offset: usize,
+}
+
+/**
+    Resolve a kit name (e.g. Arima), an enzyme name (case insensitive) or a
+    cut-site motif such as A^AGCTT. Motifs may use IUPAC ambiguity codes.
+
+    spec : kit name, enzyme name or motif
+ */
+pub fn parse_cut_sites(spec: &str) -> Result<Vec<CutSite>, Box<dyn Error>> {
+    if let Some((_, enzymes)) = DIGESTION_KITS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(spec)) {
+        return enzymes.iter().map(|enzyme| parse_cut_site(enzyme)).collect();
+    }
+    parse_cut_site(spec).map(|site| vec![site])
+}
+
+fn parse_cut_site(spec: &str) -> Result<CutSite, Box<dyn Error>> {
+    if let Some((name, motif)) = RESTRICTION_ENZYMES.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(spec)) {
+        return parse_motif(name, motif);
+    }
+    parse_motif(spec, spec)
+}
+
+fn parse_motif(name: &str, motif: &str) -> Result<CutSite, Box<dyn Error>> {
+    let upper = motif.to_ascii_uppercase();
+    let offset = upper.find('^')
+        .ok_or_else(|| format!("Unknown enzyme or cut-site motif without '^': {}", motif))?;
+    let bases: Vec<u8> = upper.bytes().filter(|&b| b != b'^').collect();
+    if bases.is_empty() || upper.matches('^').count() > 1 {
+        return Err(format!("Invalid cut-site motif: {}", motif).into());
+    }
+    if let Some(b) = bases.iter().find(|&&b| iupac_mask(b) == 0) {
+        return Err(format!("Invalid base '{}' in cut-site motif {}", *b as char, motif).into());
+    }
+    Ok(CutSite { name: name.to_string(), motif: bases, offset })
+}
+
+/// Bit mask of the nucleotides (A=1, C=2, G=4, T=8) represented by an IUPAC code
+fn iupac_mask(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => 1,
+        b'C' => 2,
+        b'G' => 4,
+        b'T' | b'U' => 8,
+        b'R' => 1 | 4,
+        b'Y' => 2 | 8,
+        b'S' => 2 | 4,
+        b'W' => 1 | 8,
+        b'K' => 4 | 8,
+        b'M' => 1 | 2,
+        b'B' => 2 | 4 | 8,
+        b'D' => 1 | 4 | 8,
+        b'H' => 1 | 2 | 8,
+        b'V' => 1 | 2 | 4,
+        b'N' => 1 | 2 | 4 | 8,
+        _ => 0,
+    }
+}
+
+fn complement_mask(mask: u8) -> u8 {
+    // A<->T, C<->G
+    ((mask & 1) << 3) | ((mask & 8) >> 3) | ((mask & 2) << 1) | ((mask & 4) >> 1)
+}
+
+/// Encode a sequence as nucleotide masks, ambiguous bases (N, ...) never matching a motif
+fn sequence_masks(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .map(|&b| {
+            let mask = iupac_mask(b);
+            if mask.count_ones() == 1 { mask } else { 0 }
+        })
+        .collect()
+}
+
+/**
+    Find all cut positions (0-based, on the forward strand) of a motif in a sequence.
+    Both strands are searched, the reverse strand cut being mirrored in the motif.
+
+    seq : chromosome sequence encoded with sequence_masks()
+    site : restriction site
+ */
+fn find_cut_positions(seq: &[u8], site: &CutSite) -> Vec<usize> {
+    let forward: Vec<u8> = site.motif.iter().map(|&b| iupac_mask(b)).collect();
+    let reverse: Vec<u8> = forward.iter().rev().map(|&m| complement_mask(m)).collect();
+    let len = forward.len();
+
+    let mut patterns = vec![(forward.clone(), site.offset)];
+    if reverse != forward {
+        patterns.push((reverse, len - site.offset));
+    }
+
+    let mut positions = Vec::new();
+    if seq.len() < len {
+        return positions;
+    }
+    for start in 0..=(seq.len() - len) {
+        let window = &seq[start..start + len];
+        for (pattern, offset) in &patterns {
+            let matched = window.iter().zip(pattern.iter())
+                .all(|(&b, &m)| b & m != 0);
+            if matched {
+                positions.push(start + offset);
+            }
+        }
+    }
+    positions
+}
+
+/**
+    Name of a restriction fragment. With multiple enzymes, the enzymes having
+    produced the left and right boundaries are appended, e.g. HIC_chr1_5:DpnII-HinfI.
+    Enzymes cutting at the same position are joined with '+', chromosome ends are '.'.
+ */
+pub fn format_fragment_name(chrom: &str, index: u64, enzymes: Option<(&str, &str)>) -> String {
+    match enzymes {
+        Some((left, right)) => format!("HIC_{}_{}:{}-{}", chrom, index, left, right),
+        None => format!("HIC_{}_{}", chrom, index),
+    }
+}
+
+/**
+    Enzymes of the left and right boundaries of a fragment name annotated by
+    split_fragments, i.e. `HIC_<chrom>_<index>:<left>-<right>`. Other names, e.g.
+    chr1:100-200 from a user BED file, are not parsed.
+ */
+pub fn parse_fragment_enzymes(name: &str) -> Option<(&str, &str)> {
+    let (fragment, enzymes) = name.rsplit_once(':')?;
+    let (left, right) = enzymes.split_once('-')?;
+    let (_, index) = fragment.strip_prefix("HIC_")?.rsplit_once('_')?;
+    (index.parse::<u64>().is_ok() && is_boundary_label(left) && is_boundary_label(right)).then_some((left, right))
+}
+
+/// Whether a label can name the enzymes of a fragment boundary, i.e. has no separator of fragment names
+fn is_boundary_label(label: &str) -> bool {
+    !label.is_empty() && !label.contains(['-', ':'])
+}
+
+/**
+    Boundary label of the fragments of a file without enzyme annotation: the
+    restriction enzyme named in the file name, e.g. DpnII for dpnii_hg38, or
+    else the file name with the separators of fragment names replaced by '_'.
+
+    file_stem : file name without directory and extension
+ */
+pub fn get_file_enzyme_label(file_stem: &str) -> String {
+    file_stem.split(|c: char| !c.is_ascii_alphanumeric())
+        .find_map(|token| RESTRICTION_ENZYMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(token)))
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| file_stem.replace(['-', ':', '+'], "_"))
+}
+
+/**
+    Build the fragments between sorted cut positions labelled by enzyme(s).
+    Labels are only added to the names when several enzymes are involved.
+
+    chrom : chromosome name
+    chrom_len : chromosome length
+    cuts : cut position -> enzymes having cut there
+    annotate : append boundary enzymes to fragment names
+ */
+pub fn split_fragments(chrom: &str, chrom_len: u64, cuts: &BTreeMap<u64, Vec<String>>,
+    annotate: bool) -> Vec<BED<6>> {
+    let mut fragments = Vec::new();
+    let mut start = 0;
+    let mut start_label = NO_ENZYME.to_string();
+    let boundaries = cuts.iter()
+        .filter(|(pos, _)| **pos > 0 && **pos < chrom_len)
+        .map(|(pos, enzymes)| (*pos, enzymes.join("+")))
+        .chain(std::iter::once((chrom_len, NO_ENZYME.to_string())));
+    for (end, end_label) in boundaries {
+        if end > start {
+            let enzymes = annotate.then_some((start_label.as_str(), end_label.as_str()));
+            let name = format_fragment_name(chrom, fragments.len() as u64 + 1, enzymes);
+            fragments.push(BED::new(chrom, start, end, Some(name), Score::try_from(0u16).ok(),
+                Some(Strand::Forward), OptionalFields::default()));
+        }
+        start = end;
+        start_label = end_label;
+    }
+    fragments
+}
+
+/**
+    Split a chromosome into restriction fragments and write them as BED6
+    (chrom, start, end, HIC_<chrom>_<n>, 0, +), as HiC-Pro digest_genome.py does.
+ */
+fn write_chrom_fragments<W: Write>(writer: &mut W, chrom: &str, seq: &[u8], sites: &[CutSite])
+    -> Result<u64, Box<dyn Error>> {
+    let masks = sequence_masks(seq);
+    let mut cuts: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    for site in sites {
+        for pos in find_cut_positions(&masks, site) {
+            let enzymes = cuts.entry(pos as u64).or_default();
+            if !enzymes.contains(&site.name) {
+                enzymes.push(site.name.clone());
+            }
+        }
+    }
+    let mut names: Vec<&str> = sites.iter().map(|site| site.name.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+    let fragments = split_fragments(chrom, seq.len() as u64, &cuts, names.len() > 1);
+    for fragment in &fragments {
+        writeln!(writer, "{}", fragment)?;
+    }
+    Ok(fragments.len() as u64)
+}
+
+/// Open a FASTA file, transparently decompressing gzip/bgzip input
+fn open_fasta(path: &Path) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let is_gzip = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    if is_gzip {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/**
+    Digest a genome in silico and write the restriction fragments BED
+
+    fasta : genome FASTA (plain, gzip or bgzip)
+    sites : restriction sites to cut
+    writer : BED output
+ */
+pub fn digest_genome<W: Write>(fasta: &Path, sites: &[CutSite], writer: &mut W)
+    -> Result<u64, Box<dyn Error>> {
+    let reader = open_fasta(fasta)?;
+    let mut chrom: Option<String> = None;
+    let mut seq: Vec<u8> = Vec::new();
+    let mut n_frag = 0;
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end();
+        if let Some(header) = line.strip_prefix('>') {
+            if let Some(name) = chrom.take() {
+                n_frag += write_chrom_fragments(writer, &name, &seq, sites)?;
+            }
+            let name = header.split_whitespace().next().unwrap_or_default();
+            info!("## Digesting {}", name);
+            chrom = Some(name.to_string());
+            seq.clear();
+        } else if chrom.is_some() {
+            seq.extend(line.bytes().map(|b| b.to_ascii_uppercase()));
+        } else if !line.is_empty() {
+            warn!("Warning: sequence found before the first FASTA header - skipped");
+        }
+    }
+    if let Some(name) = chrom.take() {
+        n_frag += write_chrom_fragments(writer, &name, &seq, sites)?;
+    }
+    Ok(n_frag)
+}
+
+/**
+    Digest a genome into a restriction fragment BED file, as the digest
+    subcommand does
+
+    fasta : genome FASTA (plain, gzip or bgzip)
+    specs : enzyme names, kits or cut-site motifs
+    output : BED file to write
+ */
+pub fn run_digest(fasta: &Path, specs: &[String], output: &Path) -> Result<(), Box<dyn Error>> {
+    let sites: Vec<CutSite> = specs.iter()
+        .map(|spec| parse_cut_sites(spec))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    for site in &sites {
+        info!("## Restriction site {}: {}", site.name, String::from_utf8_lossy(&site.motif));
+    }
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    let n_frag = digest_genome(fasta, &sites, &mut writer)?;
+    writer.flush()?;
+    info!("## {} restriction fragments written to {}", n_frag, output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bed_utils::bed::BEDLike;
+
+    fn cut_positions(seq: &str, spec: &str) -> Vec<usize> {
+        let site = parse_cut_site(spec).unwrap();
+        find_cut_positions(&sequence_masks(seq.as_bytes()), &site)
+    }
+
+    #[test]
+    fn test_parse_cut_sites() {
+        let sites = parse_cut_sites("arima").unwrap();
+        let names: Vec<&str> = sites.iter().map(|site| site.name.as_str()).collect();
+        assert_eq!(names, ["DpnII", "HinfI"]);
+        let site = parse_cut_site("hindiii").unwrap();
+        assert_eq!((site.name.as_str(), site.motif.as_slice(), site.offset), ("HindIII", b"AAGCTT".as_slice(), 1));
+        assert!(parse_cut_site("GATC").is_err());
+        assert!(parse_cut_site("G^AT^C").is_err());
+        assert!(parse_cut_site("G^AXC").is_err());
+    }
+
+    #[test]
+    fn test_palindromic_cut_positions() {
+        assert_eq!(cut_positions("AAGATCAAGATC", "DpnII"), [2, 8]);
+        assert_eq!(cut_positions("TTAAGCTTAA", "HindIII"), [3]);
+        assert_eq!(cut_positions("ACATGA", "NlaIII"), [5]);
+        // Degenerate motif, but never matching an N of the sequence
+        assert_eq!(cut_positions("GAATCGACTCGANTC", "HinfI"), [1, 6]);
+    }
+
+    #[test]
+    fn test_reverse_strand_cut_positions() {
+        // GGT^AC on the forward strand
+        assert_eq!(cut_positions("AAGGTACAA", "GGT^AC"), [5]);
+        // GTACC is GGT^AC on the reverse strand, cut 3 bases from its 5' end, i.e. at 7 - 3
+        assert_eq!(cut_positions("AAGTACCAA", "GGT^AC"), [4]);
+        assert_eq!(cut_positions("aagtaccaa", "GGT^AC"), [4]);
+        // Cuts at the motif ends
+        assert_eq!(cut_positions("AGGTACA", "^GGTAC"), [1]);
+        assert_eq!(cut_positions("AGTACCA", "^GGTAC"), [6]);
+    }
+
+    #[test]
+    fn test_split_fragments() {
+        let mut cuts: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        cuts.insert(0, vec!["DpnII".to_string()]);
+        cuts.insert(10, vec!["DpnII".to_string(), "HinfI".to_string()]);
+        cuts.insert(25, vec!["HinfI".to_string()]);
+        let fragments = split_fragments("chr1", 30, &cuts, true);
+        let fragments: Vec<(u64, u64, &str)> = fragments.iter()
+            .map(|f| (f.start(), f.end(), f.name().unwrap()))
+            .collect();
+        assert_eq!(fragments, [
+            (0, 10, "HIC_chr1_1:.-DpnII+HinfI"),
+            (10, 25, "HIC_chr1_2:DpnII+HinfI-HinfI"),
+            (25, 30, "HIC_chr1_3:HinfI-."),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_fragment_enzymes() {
+        assert_eq!(parse_fragment_enzymes("HIC_chr1_2:DpnII+HinfI-HinfI"), Some(("DpnII+HinfI", "HinfI")));
+        assert_eq!(parse_fragment_enzymes("HIC_chr1_1:.-DpnII"), Some((".", "DpnII")));
+        assert_eq!(parse_fragment_enzymes("HIC_chr1_3:G^ANTC-."), Some(("G^ANTC", ".")));
+        assert_eq!(parse_fragment_enzymes("HIC_chr1_3:my_enzyme-DpnII"), Some(("my_enzyme", "DpnII")));
+        assert_eq!(parse_fragment_enzymes("HIC_chr1_3"), None);
+        // Names from user fragment files are not boundary annotations
+        assert_eq!(parse_fragment_enzymes("chr1:100-200"), None);
+        assert_eq!(parse_fragment_enzymes("HIC_chr1:100-200"), None);
+    }
+
+    #[test]
+    fn test_get_file_enzyme_label() {
+        assert_eq!(get_file_enzyme_label("dpnii_hg38"), "DpnII");
+        assert_eq!(get_file_enzyme_label("hg38.HINFI"), "HinfI");
+        assert_eq!(get_file_enzyme_label("my-enzyme:v1+2"), "my_enzyme_v1_2");
+    }
+}
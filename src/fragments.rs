@@ -0,0 +1,227 @@
+//! Restriction fragment loading and overlap index
+
+use crate::classify::DumpReason;
+use crate::digest;
+use crate::reads::get_read_pos;
+use bed_utils::bed::{io::Reader, BEDLike, BED};
+use bed_utils::intervaltree::{Interval, Lapper};
+use log::warn;
+use noodles_bam as bam;
+use noodles_sam as sam;
+use noodles_sam::alignment::Record;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::path::PathBuf;
+
+/// Restriction fragments of each chromosome, indexed for overlap queries
+pub type FragmentIndex = HashMap<String, Lapper<u64, BED<6>>>;
+
+/// Restriction fragment of a read, None without fragment index or for an unmapped read
+pub type FragmentLookup = Result<Option<BED<6>>, DumpReason>;
+
+/// Restriction fragment overlapping the middle of a read, or why there is not exactly one
+pub(crate) fn get_overlapping_restriction_fragment(res_frag : &FragmentIndex, 
+    chrom: &str, read:  &bam::Record) -> Result<BED<6>, DumpReason> {
+    let pos = get_read_pos(read, "middle").ok_or(DumpReason::NoFragment)?;
+    if let Some(lapper) = res_frag.get(chrom) {
+        let overlapping_frag : Vec<_> = lapper.find(pos as u64, pos as u64 + 1).collect();
+        if overlapping_frag.len() > 1{
+            warn!("Warning: {} restriction fragments found for {} - skipped", 
+                     overlapping_frag.len(), read.name().unwrap().to_string());
+            return Err(DumpReason::AmbiguousFragment)
+        } else if overlapping_frag.len() == 0 {
+            warn!("Warning: {} restriction fragments found for {} - skipped", 
+                     overlapping_frag.len(), read.name().unwrap().to_string());
+            return Err(DumpReason::NoFragment)
+        } else{
+            //let test = &overlapping_frag[0].val;
+            return Ok(overlapping_frag[0].val.clone())
+        }
+    } else{
+        warn!("Warning: No restriction fragments found for {} - skipped", 
+                     read.name().unwrap().to_string());
+        return Err(DumpReason::NoFragment)
+    }
+}
+
+/**
+    Load restriction fragments from one or several BED files. Several files
+    (e.g. one digestion per enzyme) are merged into a single fragment map whose
+    boundaries are labelled with the enzymes they come from, i.e. the enzymes
+    annotated in the fragment names or else the enzymes named by the files.
+ */
+pub fn load_restriction_fragments(files: &[String]) -> Result<Vec<BED<6>>, Box<dyn Error>> {
+    let mut sets = Vec::new();
+    for file in files {
+        let bed_reader = Reader::new(File::open(file)?, None);
+        let bed_rec: Vec<BED<6>> = bed_reader.into_records::<BED<6>>()
+            .map(|r| r.unwrap())
+            .collect();
+        sets.push((file, bed_rec));
+    }
+    if sets.len() == 1 {
+        return Ok(sets.pop().map(|(_, bed_rec)| bed_rec).unwrap_or_default());
+    }
+
+    let mut chroms: Vec<String> = Vec::new();
+    let mut chrom_len: HashMap<String, u64> = HashMap::new();
+    let mut cuts: HashMap<String, BTreeMap<u64, Vec<String>>> = HashMap::new();
+    for (file, bed_rec) in &sets {
+        let file_label = digest::get_file_enzyme_label(PathBuf::from(file).file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or(file.as_str()));
+        let mut set_len: HashMap<&str, u64> = HashMap::new();
+        for bed in bed_rec {
+            let len = set_len.entry(bed.chrom()).or_default();
+            *len = (*len).max(bed.end());
+        }
+        for bed in bed_rec {
+            if !chrom_len.contains_key(bed.chrom()) {
+                chroms.push(bed.chrom().to_string());
+            }
+            let len = chrom_len.entry(bed.chrom().to_string()).or_default();
+            *len = (*len).max(bed.end());
+
+            let (left, right) = bed.name()
+                .and_then(digest::parse_fragment_enzymes)
+                .unwrap_or((&file_label, &file_label));
+            let chrom_cuts = cuts.entry(bed.chrom().to_string()).or_default();
+            let mut add_cut = |pos: u64, label: &str| {
+                let enzymes = chrom_cuts.entry(pos).or_default();
+                for enzyme in label.split('+').filter(|e| *e != digest::NO_ENZYME) {
+                    if !enzymes.iter().any(|e| e == enzyme) {
+                        enzymes.push(enzyme.to_string());
+                    }
+                }
+            };
+            if bed.start() > 0 {
+                add_cut(bed.start(), left);
+            }
+            if bed.end() < set_len[bed.chrom()] {
+                add_cut(bed.end(), right);
+            }
+        }
+    }
+
+    Ok(chroms.iter()
+        .flat_map(|chrom| digest::split_fragments(chrom, chrom_len[chrom], &cuts[chrom], true))
+        .collect())
+}
+
+/// Keep the restriction fragments within the size limits, when given
+pub fn filter_fragments_by_size(fragments: Vec<BED<6>>, min_size: Option<u64>, max_size: Option<u64>) -> Vec<BED<6>> {
+    fragments.into_iter()
+        .filter(|bed| {
+            let frag_len = bed.end() - bed.start();
+            min_size.is_none_or(|min_size| frag_len >= min_size)
+                && max_size.is_none_or(|max_size| frag_len <= max_size)
+        })
+        .collect()
+}
+
+/// Build the per-chromosome interval index of BED records
+pub fn convert_vec_to_lapper<B: BEDLike + Clone>(
+    bed_records: &Vec<B>
+ ) -> HashMap<String, Lapper<u64,B>> {
+    let mut chrom_to_interval : HashMap<String, Vec<Interval<u64, B>>> = HashMap::new();
+
+    for bed in bed_records {
+        let chrom = bed.chrom().to_string();
+        let interval = Interval {
+            start : bed.start(),
+            stop : bed.end(),
+            val : bed.clone()
+        };
+
+        chrom_to_interval
+            .entry(chrom)
+            .or_insert_with(Vec::new)
+            .push(interval);
+    }
+
+    chrom_to_interval
+        .into_iter()
+        .map(|(chrom,intervals)| (chrom, Lapper::new(intervals)))
+        .collect()
+}
+
+/// Sorted restriction fragment ends of each chromosome, in the BAM header order
+pub fn get_fragment_sites(chrom_sizes: &[(String, u64)], fragments: &[BED<6>]) -> Vec<Vec<u64>> {
+    let chrom_index: HashMap<&str, usize> = chrom_sizes.iter().enumerate()
+        .map(|(tid, (name, _))| (name.as_str(), tid))
+        .collect();
+    let mut sites = vec![Vec::new(); chrom_sizes.len()];
+    for fragment in fragments {
+        if let Some(&tid) = chrom_index.get(fragment.chrom()) {
+            sites[tid].push(fragment.end());
+        }
+    }
+    for chrom_sites in sites.iter_mut() {
+        chrom_sites.sort_unstable();
+        chrom_sites.dedup();
+    }
+    sites
+}
+
+/// Reference name and overlapping restriction fragment of a mapped read
+pub fn get_read_location(read: &bam::Record, headers: &sam::Header,
+    bed_ladder: &FragmentIndex, enzyme_free: bool)
+    -> Result<(Option<String>, FragmentLookup), Box<dyn Error>> {
+    if read.flags().is_unmapped() {
+        return Ok((None, Ok(None)));
+    }
+    match read.reference_sequence(headers) {
+        Some(result) => {
+            let (name_bytes, _) = result?;
+            let chrom = std::str::from_utf8(name_bytes)?.to_string();
+            let res_frag = if enzyme_free {
+                Ok(None)
+            } else {
+                get_overlapping_restriction_fragment(bed_ladder, &chrom, read).map(Some)
+            };
+            Ok((Some(chrom), res_frag))
+        }
+        None => Ok((None, Err(DumpReason::NoReference))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify::get_ligation_junction;
+    use crate::input::tests::encode_records;
+
+    #[test]
+    fn test_merge_unannotated_fragment_files() {
+        let dir = std::env::temp_dir().join(format!("hic2frag_fragments_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [("dpnii_hg38.bed", [0, 100, 1000]), ("my-enzyme.bed", [0, 500, 1000])].map(|(name, cuts)| {
+            let path = dir.join(name);
+            let lines: String = cuts.windows(2).enumerate()
+                .map(|(i, bounds)| format!("chr1\t{}\t{}\tfrag{}\t0\t+\n", bounds[0], bounds[1], i))
+                .collect();
+            std::fs::write(&path, lines).unwrap();
+            path.to_str().unwrap().to_string()
+        });
+        let fragments = load_restriction_fragments(&files).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<_> = fragments.iter().map(|f| (f.start(), f.end(), f.name().unwrap())).collect();
+        assert_eq!(names, [
+            (0, 100, "HIC_chr1_1:.-DpnII"),
+            (100, 500, "HIC_chr1_2:DpnII-my_enzyme"),
+            (500, 1000, "HIC_chr1_3:my_enzyme-."),
+        ]);
+
+        // Forward read towards the my_enzyme end, reverse read towards the DpnII end
+        let reads = encode_records(&[
+            "read1\t65\tchr1\t151\t60\t10M\tchr1\t601\t0\tACGTACGTAC\t*",
+            "read1\t145\tchr1\t601\t60\t10M\tchr1\t151\t0\tACGTACGTAC\t*",
+        ]);
+        let junction = get_ligation_junction(&reads[0], &fragments[1], &reads[1], &fragments[2]);
+        assert_eq!(junction.as_deref(), Some("my_enzyme-my_enzyme"));
+        let junction = get_ligation_junction(&reads[0], &fragments[1], &reads[1], &fragments[1]);
+        assert_eq!(junction.as_deref(), Some("DpnII-my_enzyme"));
+    }
+}
@@ -0,0 +1,508 @@
+//! Juicer .hic (version 8) writer
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Base-pair resolutions written by juicer_tools pre by default
+pub const DEFAULT_BP_RESOLUTIONS: &[u64] = &[
+    2_500_000, 1_000_000, 500_000, 250_000, 100_000, 50_000, 25_000, 10_000, 5_000,
+];
+
+const HIC_VERSION: i32 = 8;
+// Side of a block, in bins
+const BLOCK_BIN_COUNT: u64 = 1000;
+// Number of bins of the whole-genome ("All") matrix
+const WHOLE_GENOME_BINS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HicUnit {
+    Bp,
+    Frag,
+}
+
+impl HicUnit {
+    fn name(&self) -> &'static str {
+        match self {
+            HicUnit::Bp => "BP",
+            HicUnit::Frag => "FRAG",
+        }
+    }
+}
+
+/// Contacts of one resolution, by (chr1 index, chr2 index) then (binX, binY)
+#[derive(Debug)]
+struct HicZoom {
+    unit: HicUnit,
+    res_index: i32,
+    bin_size: u64,
+    counts: HashMap<(usize, usize), HashMap<(u64, u64), f32>>,
+}
+
+impl HicZoom {
+    fn new(unit: HicUnit, res_index: usize, bin_size: u64) -> Self {
+        HicZoom { unit, res_index: res_index as i32, bin_size, counts: HashMap::new() }
+    }
+
+    fn add(&mut self, chr1: usize, bin1: u64, chr2: usize, bin2: u64) {
+        let key = if chr1 == chr2 { (bin1.min(bin2), bin1.max(bin2)) } else { (bin1, bin2) };
+        *self.counts.entry((chr1, chr2)).or_default().entry(key).or_default() += 1.0;
+    }
+}
+
+/**
+    Juicer .hic (version 8) builder. Chromosome 0 is the whole-genome "All"
+    pseudo-chromosome, in kb, as written by juicer_tools pre; the BAM header
+    chromosomes follow from index 1.
+ */
+#[derive(Debug)]
+pub struct HicFile {
+    chroms: Vec<(String, u64)>,
+    // Fragment ends per chromosome, for fragment-level resolutions
+    sites: Vec<Vec<u64>>,
+    chrom_offsets: Vec<u64>,
+    zooms: Vec<HicZoom>,
+    whole_genome: HicZoom,
+}
+
+impl HicFile {
+    /**
+        chroms : chromosome names and sizes, in the BAM header order
+        bp_resolutions : base-pair bin sizes
+        frag_resolutions : bin sizes in restriction fragments
+        sites : sorted fragment ends of each chromosome (may be empty without fragment resolutions)
+     */
+    pub fn new(chroms: &[(String, u64)], bp_resolutions: &[u64], frag_resolutions: &[u64],
+        sites: Vec<Vec<u64>>) -> Self {
+        let mut bp_resolutions = bp_resolutions.to_vec();
+        bp_resolutions.sort_unstable_by(|a, b| b.cmp(a));
+        bp_resolutions.dedup();
+        let mut frag_resolutions = frag_resolutions.to_vec();
+        frag_resolutions.sort_unstable_by(|a, b| b.cmp(a));
+        frag_resolutions.dedup();
+
+        let zooms = bp_resolutions.iter().enumerate()
+            .map(|(i, &res)| HicZoom::new(HicUnit::Bp, i, res))
+            .chain(frag_resolutions.iter().enumerate()
+                .map(|(i, &res)| HicZoom::new(HicUnit::Frag, i, res)))
+            .collect();
+
+        let mut chrom_offsets = Vec::with_capacity(chroms.len());
+        let mut genome_len = 0;
+        for (_, len) in chroms {
+            chrom_offsets.push(genome_len);
+            genome_len += len;
+        }
+        let whole_genome_bin = (genome_len / 1000 / WHOLE_GENOME_BINS).max(1);
+
+        HicFile {
+            chroms: chroms.to_vec(),
+            sites,
+            chrom_offsets,
+            zooms,
+            whole_genome: HicZoom::new(HicUnit::Bp, 0, whole_genome_bin),
+        }
+    }
+
+    fn genome_len_kb(&self) -> u64 {
+        self.chroms.iter().map(|(_, len)| len).sum::<u64>() / 1000
+    }
+
+    /// Restriction fragment index of a 0-based position
+    fn get_fragment(&self, tid: usize, pos0: u64) -> u64 {
+        self.sites[tid].partition_point(|&end| end <= pos0) as u64
+    }
+
+    /// Add a contact between two 1-based positions on chromosomes given by reference id
+    pub fn add_contact(&mut self, tid1: usize, pos1: u64, tid2: usize, pos2: u64) {
+        if tid1 >= self.chroms.len() || tid2 >= self.chroms.len() {
+            return;
+        }
+        let ((tid1, pos1), (tid2, pos2)) = if (tid1, pos1) <= (tid2, pos2) {
+            ((tid1, pos1), (tid2, pos2))
+        } else {
+            ((tid2, pos2), (tid1, pos1))
+        };
+        let (pos1, pos2) = (pos1.saturating_sub(1), pos2.saturating_sub(1));
+
+        for i in 0..self.zooms.len() {
+            let (unit, bin_size) = (self.zooms[i].unit, self.zooms[i].bin_size);
+            let (bin1, bin2) = match unit {
+                HicUnit::Bp => (pos1 / bin_size, pos2 / bin_size),
+                HicUnit::Frag => (self.get_fragment(tid1, pos1) / bin_size, self.get_fragment(tid2, pos2) / bin_size),
+            };
+            self.zooms[i].add(tid1 + 1, bin1, tid2 + 1, bin2);
+        }
+
+        let all1 = (self.chrom_offsets[tid1] + pos1) / 1000 / self.whole_genome.bin_size;
+        let all2 = (self.chrom_offsets[tid2] + pos2) / 1000 / self.whole_genome.bin_size;
+        self.whole_genome.add(0, all1, 0, all2);
+    }
+
+    /// Number of bins of a chromosome (by .hic index) at a zoom level
+    fn n_bins(&self, zoom: &HicZoom, chr: usize) -> u64 {
+        if chr == 0 {
+            return self.genome_len_kb() / zoom.bin_size + 1;
+        }
+        match zoom.unit {
+            HicUnit::Bp => self.chroms[chr - 1].1 / zoom.bin_size + 1,
+            HicUnit::Frag => self.sites[chr - 1].len() as u64 / zoom.bin_size + 1,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let bp_resolutions: Vec<u64> = self.zooms.iter()
+            .filter(|zoom| zoom.unit == HicUnit::Bp)
+            .map(|zoom| zoom.bin_size)
+            .collect();
+        let frag_resolutions: Vec<u64> = self.zooms.iter()
+            .filter(|zoom| zoom.unit == HicUnit::Frag)
+            .map(|zoom| zoom.bin_size)
+            .collect();
+
+        // Header
+        writer.write_all(b"HIC\0")?;
+        write_i32(&mut writer, HIC_VERSION)?;
+        write_i64(&mut writer, 0)?; // master index position, set at the end
+        write_str(&mut writer, "unknown")?;
+        write_i32(&mut writer, 1)?;
+        write_str(&mut writer, "software")?;
+        write_str(&mut writer, &format!("hic2frag {}", env!("CARGO_PKG_VERSION")))?;
+        write_i32(&mut writer, self.chroms.len() as i32 + 1)?;
+        write_str(&mut writer, "All")?;
+        write_i32(&mut writer, self.genome_len_kb() as i32)?;
+        for (name, len) in &self.chroms {
+            write_str(&mut writer, name)?;
+            write_i32(&mut writer, *len as i32)?;
+        }
+        write_i32(&mut writer, bp_resolutions.len() as i32)?;
+        for res in &bp_resolutions {
+            write_i32(&mut writer, *res as i32)?;
+        }
+        write_i32(&mut writer, frag_resolutions.len() as i32)?;
+        for res in &frag_resolutions {
+            write_i32(&mut writer, *res as i32)?;
+        }
+        if !frag_resolutions.is_empty() {
+            write_i32(&mut writer, 0)?; // no sites for "All"
+            for chrom_sites in &self.sites {
+                write_i32(&mut writer, chrom_sites.len() as i32)?;
+                for site in chrom_sites {
+                    write_i32(&mut writer, *site as i32)?;
+                }
+            }
+        }
+
+        // Body: the blocks then the metadata of each matrix
+        let mut master_index: Vec<(String, u64, u64)> = Vec::new();
+        let whole_genome = [&self.whole_genome];
+        let chrom_zooms: Vec<&HicZoom> = self.zooms.iter().collect();
+        master_index.push(self.write_matrix(&mut writer, 0, 0, &whole_genome)?);
+        let chrom_pairs: BTreeSet<(usize, usize)> = self.zooms.iter()
+            .flat_map(|zoom| zoom.counts.keys().copied())
+            .collect();
+        for (chr1, chr2) in chrom_pairs {
+            master_index.push(self.write_matrix(&mut writer, chr1, chr2, &chrom_zooms)?);
+        }
+
+        // Footer: master index and (empty) expected values and normalizations
+        let master_index_position = writer.stream_position()?;
+        let mut footer = Vec::new();
+        write_i32(&mut footer, master_index.len() as i32)?;
+        for (key, position, size) in &master_index {
+            write_str(&mut footer, key)?;
+            write_i64(&mut footer, *position as i64)?;
+            write_i32(&mut footer, *size as i32)?;
+        }
+        write_i32(&mut footer, 0)?; // expected value vectors
+        write_i32(&mut writer, footer.len() as i32)?;
+        writer.write_all(&footer)?;
+        write_i32(&mut writer, 0)?; // normalized expected value vectors
+        write_i32(&mut writer, 0)?; // normalization vectors
+
+        writer.seek(SeekFrom::Start(8))?;
+        write_i64(&mut writer, master_index_position as i64)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /**
+        Write the blocks of a chromosome pair matrix at all zoom levels, then its
+        metadata. Return the master index entry (key, position, size).
+     */
+    fn write_matrix<W: Write + Seek>(&self, writer: &mut W, chr1: usize, chr2: usize,
+        zooms: &[&HicZoom]) -> Result<(String, u64, u64), Box<dyn Error>> {
+        let mut zoom_indexes = Vec::with_capacity(zooms.len());
+        for zoom in zooms {
+            let n_bins = self.n_bins(zoom, chr1).max(self.n_bins(zoom, chr2));
+            let block_column_count = n_bins / BLOCK_BIN_COUNT + 1;
+            let mut blocks: BTreeMap<u64, Vec<(u64, u64, f32)>> = BTreeMap::new();
+            let mut sum_counts = 0.0;
+            if let Some(counts) = zoom.counts.get(&(chr1, chr2)) {
+                for (&(bin_x, bin_y), &count) in counts {
+                    let block = (bin_y / BLOCK_BIN_COUNT) * block_column_count + bin_x / BLOCK_BIN_COUNT;
+                    blocks.entry(block).or_default().push((bin_x, bin_y, count));
+                    sum_counts += count;
+                }
+            }
+            let mut block_index = Vec::with_capacity(blocks.len());
+            for (block, mut records) in blocks {
+                let position = writer.stream_position()?;
+                let data = encode_block(&mut records)?;
+                writer.write_all(&data)?;
+                block_index.push((block, position, data.len() as u64));
+            }
+            zoom_indexes.push((zoom, sum_counts, block_column_count, block_index));
+        }
+
+        let position = writer.stream_position()?;
+        let mut metadata = Vec::new();
+        write_i32(&mut metadata, chr1 as i32)?;
+        write_i32(&mut metadata, chr2 as i32)?;
+        write_i32(&mut metadata, zoom_indexes.len() as i32)?;
+        for (zoom, sum_counts, block_column_count, block_index) in zoom_indexes {
+            write_str(&mut metadata, zoom.unit.name())?;
+            write_i32(&mut metadata, zoom.res_index)?;
+            write_f32(&mut metadata, sum_counts)?;
+            write_f32(&mut metadata, 0.0)?; // occupiedCellCount
+            write_f32(&mut metadata, 0.0)?; // stdDev
+            write_f32(&mut metadata, 0.0)?; // percent95
+            write_i32(&mut metadata, zoom.bin_size as i32)?;
+            write_i32(&mut metadata, BLOCK_BIN_COUNT as i32)?;
+            write_i32(&mut metadata, block_column_count as i32)?;
+            write_i32(&mut metadata, block_index.len() as i32)?;
+            for (block, block_position, size) in block_index {
+                write_i32(&mut metadata, block as i32)?;
+                write_i64(&mut metadata, block_position as i64)?;
+                write_i32(&mut metadata, size as i32)?;
+            }
+        }
+        writer.write_all(&metadata)?;
+        Ok((format!("{}_{}", chr1, chr2), position, metadata.len() as u64))
+    }
+}
+
+/**
+    Encode a block as zlib-compressed "list of rows" records with float counts
+
+    records : (binX, binY, count) of the block
+ */
+fn encode_block(records: &mut [(u64, u64, f32)]) -> Result<Vec<u8>, Box<dyn Error>> {
+    records.sort_unstable_by_key(|r| (r.1, r.0));
+    let bin_x_offset = records.iter().map(|r| r.0).min().unwrap_or(0);
+    let bin_y_offset = records.iter().map(|r| r.1).min().unwrap_or(0);
+
+    let mut buf = Vec::new();
+    write_i32(&mut buf, records.len() as i32)?;
+    write_i32(&mut buf, bin_x_offset as i32)?;
+    write_i32(&mut buf, bin_y_offset as i32)?;
+    buf.push(1); // counts stored as floats (0 means shorts)
+    buf.push(1); // list of rows representation
+
+    let rows: Vec<&[(u64, u64, f32)]> = records.chunk_by(|a, b| a.1 == b.1).collect();
+    write_i16(&mut buf, rows.len() as i16)?;
+    for row in rows {
+        write_i16(&mut buf, (row[0].1 - bin_y_offset) as i16)?;
+        write_i16(&mut buf, row.len() as i16)?;
+        for &(bin_x, _, count) in row {
+            write_i16(&mut buf, (bin_x - bin_x_offset) as i16)?;
+            write_f32(&mut buf, count)?;
+        }
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&buf)?;
+    Ok(encoder.finish()?)
+}
+
+fn write_i16<W: Write>(writer: &mut W, value: i16) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_i32<W: Write>(writer: &mut W, value: i32) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_i64<W: Write>(writer: &mut W, value: i64) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_f32<W: Write>(writer: &mut W, value: f32) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+/// Null-terminated string
+fn write_str<W: Write>(writer: &mut W, value: &str) -> std::io::Result<()> {
+    writer.write_all(value.as_bytes())?;
+    writer.write_all(&[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    /// Little-endian reader over the bytes of a .hic file
+    struct HicReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> HicReader<'a> {
+        fn at(data: &'a [u8], pos: usize) -> Self {
+            HicReader { data, pos }
+        }
+
+        fn bytes<const N: usize>(&mut self) -> [u8; N] {
+            let bytes = self.data[self.pos..self.pos + N].try_into().unwrap();
+            self.pos += N;
+            bytes
+        }
+
+        fn i32(&mut self) -> i32 {
+            i32::from_le_bytes(self.bytes())
+        }
+
+        fn i64(&mut self) -> i64 {
+            i64::from_le_bytes(self.bytes())
+        }
+
+        fn f32(&mut self) -> f32 {
+            f32::from_le_bytes(self.bytes())
+        }
+
+        fn str(&mut self) -> String {
+            let end = self.pos + self.data[self.pos..].iter().position(|&b| b == 0).unwrap();
+            let value = String::from_utf8(self.data[self.pos..end].to_vec()).unwrap();
+            self.pos = end + 1;
+            value
+        }
+    }
+
+    fn write_test_file(name: &str, hic: &HicFile) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("hic2frag_test_{}_{}.hic", name, std::process::id()));
+        hic.write(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        data
+    }
+
+    fn test_file() -> HicFile {
+        let chroms = vec![("chr1".to_string(), 5_000_000), ("chr2".to_string(), 3_000_000)];
+        let sites = vec![vec![1000, 2000, 5_000_000], vec![3_000_000]];
+        let mut hic = HicFile::new(&chroms, &[1_000_000, 100_000], &[1], sites);
+        hic.add_contact(0, 1500, 0, 4_200_000);
+        hic.add_contact(0, 2500, 1, 10);
+        hic.add_contact(1, 20, 1, 2_900_000);
+        hic.add_contact(0, 1600, 0, 4_300_000);
+        hic
+    }
+
+    #[test]
+    fn test_get_fragment() {
+        let hic = test_file();
+        assert_eq!(hic.get_fragment(0, 0), 0);
+        assert_eq!(hic.get_fragment(0, 999), 0);
+        assert_eq!(hic.get_fragment(0, 1000), 1);
+        assert_eq!(hic.get_fragment(0, 4_999_999), 2);
+    }
+
+    #[test]
+    fn test_header() {
+        let data = write_test_file("header", &test_file());
+        let mut reader = HicReader::at(&data, 0);
+        assert_eq!(&reader.bytes::<4>(), b"HIC\0");
+        assert_eq!(reader.i32(), HIC_VERSION);
+        let master_index_position = reader.i64() as usize;
+        assert!(master_index_position < data.len());
+        assert_eq!(reader.str(), "unknown");
+        assert_eq!(reader.i32(), 1);
+        assert_eq!(reader.str(), "software");
+        assert!(reader.str().starts_with("hic2frag"));
+        assert_eq!(reader.i32(), 3);
+        assert_eq!((reader.str(), reader.i32()), ("All".to_string(), 8000));
+        assert_eq!((reader.str(), reader.i32()), ("chr1".to_string(), 5_000_000));
+        assert_eq!((reader.str(), reader.i32()), ("chr2".to_string(), 3_000_000));
+        // Resolutions from the coarsest
+        assert_eq!((reader.i32(), reader.i32(), reader.i32()), (2, 1_000_000, 100_000));
+        assert_eq!((reader.i32(), reader.i32()), (1, 1));
+        // Fragment sites of All, chr1 and chr2
+        assert_eq!(reader.i32(), 0);
+        assert_eq!((reader.i32(), reader.i32(), reader.i32(), reader.i32()), (3, 1000, 2000, 5_000_000));
+        assert_eq!((reader.i32(), reader.i32()), (1, 3_000_000));
+    }
+
+    #[test]
+    fn test_master_index_and_footer() {
+        let data = write_test_file("footer", &test_file());
+        let master_index_position = HicReader::at(&data, 8).i64() as usize;
+
+        let mut reader = HicReader::at(&data, master_index_position);
+        let n_bytes = reader.i32() as usize;
+        // The footer ends with the empty normalized expected values and normalization vectors
+        assert_eq!(master_index_position + 4 + n_bytes + 8, data.len());
+        let n_entries = reader.i32();
+        let entries: Vec<(String, usize, usize)> = (0..n_entries)
+            .map(|_| (reader.str(), reader.i64() as usize, reader.i32() as usize))
+            .collect();
+        let keys: Vec<&str> = entries.iter().map(|(key, _, _)| key.as_str()).collect();
+        assert_eq!(keys, ["0_0", "1_1", "1_2", "2_2"]);
+        assert_eq!(reader.i32(), 0); // expected value vectors
+        assert_eq!(reader.pos, master_index_position + 4 + n_bytes);
+
+        // Each entry points to the metadata of its matrix, ending where the next one starts
+        for (i, (key, position, size)) in entries.iter().enumerate() {
+            let mut matrix = HicReader::at(&data, *position);
+            assert_eq!(format!("{}_{}", matrix.i32(), matrix.i32()), *key);
+            let n_zooms = matrix.i32();
+            assert_eq!(n_zooms, if key == "0_0" { 1 } else { 3 });
+            let mut total = 0.0;
+            for _ in 0..n_zooms {
+                let (unit, _res_index, sum_counts) = (matrix.str(), matrix.i32(), matrix.f32());
+                assert!(unit == "BP" || unit == "FRAG");
+                let _ = (matrix.f32(), matrix.f32(), matrix.f32(), matrix.i32(), matrix.i32(), matrix.i32());
+                let n_blocks = matrix.i32();
+                let mut block_counts = 0.0;
+                for _ in 0..n_blocks {
+                    let (_block, block_position, block_size) = (matrix.i32(), matrix.i64() as usize, matrix.i32() as usize);
+                    assert!(block_position + block_size <= *position);
+                    let mut block = Vec::new();
+                    ZlibDecoder::new(&data[block_position..block_position + block_size]).read_to_end(&mut block).unwrap();
+                    let mut records = HicReader::at(&block, 0);
+                    let n_records = records.i32();
+                    let _ = (records.i32(), records.i32(), records.bytes::<2>());
+                    let n_rows = i16::from_le_bytes(records.bytes());
+                    let mut n_read = 0;
+                    for _ in 0..n_rows {
+                        let _row = i16::from_le_bytes(records.bytes());
+                        for _ in 0..i16::from_le_bytes(records.bytes()) {
+                            let _column = i16::from_le_bytes(records.bytes());
+                            block_counts += records.f32();
+                            n_read += 1;
+                        }
+                    }
+                    assert_eq!(n_read, n_records);
+                    assert_eq!(records.pos, block.len());
+                }
+                assert_eq!(block_counts, sum_counts);
+                total += sum_counts;
+            }
+            assert_eq!(matrix.pos, position + size);
+            if let Some((_, next_position, _)) = entries.get(i + 1) {
+                assert!(position + size <= *next_position);
+            }
+            let contacts = match key.as_str() {
+                "0_0" => 4.0,
+                "1_1" => 2.0 * 3.0,
+                _ => 3.0,
+            };
+            assert_eq!(total, contacts);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Contact-distance decay (P(s)) and insert-size histograms
+
+use crate::classify::{InteractionType, Orientation};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Log bins per decade of the cis-distance histograms
+pub const CIS_BINS_PER_DECADE: u32 = 10;
+
+/// Bin size (bp) of the insert-size histograms
+pub const INSERT_SIZE_BIN: u64 = 10;
+
+/// Classes with an insert-size histogram, in report order
+pub const INSERT_SIZE_CLASSES: [InteractionType; 4] = [
+    InteractionType::Valid,
+    InteractionType::DanglingEnd,
+    InteractionType::SelfCircle,
+    InteractionType::Religation,
+];
+
+/// Bin boundaries of a histogram
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binning {
+    /// Bins of constant width (bp)
+    Linear(u64),
+    /// Bins of constant width in log10 scale, as bins per decade
+    Log(u32),
+}
+
+/// Counts of values per bin, only non-empty bins being stored
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    pub binning: Binning,
+    counts: BTreeMap<u64, u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new(binning: Binning) -> Self {
+        Histogram { binning, counts: BTreeMap::new(), total: 0 }
+    }
+
+    /// Start of a bin (bp, inclusive)
+    pub fn bin_start(&self, bin: u64) -> u64 {
+        match self.binning {
+            Binning::Linear(width) => bin * width,
+            Binning::Log(per_decade) => 10f64.powf(bin as f64 / per_decade as f64).ceil() as u64,
+        }
+    }
+
+    /// Bin of a value. In log scale, values below 1 bp fall in the first bin.
+    pub fn get_bin(&self, value: u64) -> u64 {
+        match self.binning {
+            Binning::Linear(width) => value / width,
+            Binning::Log(per_decade) => {
+                let value = value.max(1);
+                let mut bin = ((value as f64).log10() * per_decade as f64).floor() as u64;
+                // Guard against rounding at the bin boundaries
+                while bin > 0 && self.bin_start(bin) > value {
+                    bin -= 1;
+                }
+                while self.bin_start(bin + 1) <= value {
+                    bin += 1;
+                }
+                bin
+            }
+        }
+    }
+
+    pub fn add(&mut self, value: u64) {
+        let bin = self.get_bin(value);
+        *self.counts.entry(bin).or_default() += 1;
+        self.total += 1;
+    }
+
+    /// Add the counts of a histogram with the same binning
+    pub fn merge(&mut self, other: &Histogram) {
+        debug_assert_eq!(self.binning, other.binning);
+        for (&bin, count) in &other.counts {
+            *self.counts.entry(bin).or_default() += count;
+        }
+        self.total += other.total;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Non-empty bins as (start, end, count), end being exclusive
+    pub fn bins(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        self.counts.iter().map(|(&bin, &count)| (self.bin_start(bin), self.bin_start(bin + 1), count))
+    }
+}
+
+/**
+    Write the <base>.cisDistance.tsv report: log-binned distances between the
+    reads of intrachromosomal valid pairs, per orientation. The contact
+    probability is the fraction of the pairs of the orientation in the bin,
+    divided by the bin width, i.e. the P(s) curve.
+ */
+pub fn write_cis_distance_report(histograms: &BTreeMap<Orientation, Histogram>, output_dir: &Path,
+    base_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("{}.cisDistance.tsv", base_name)))?);
+    writeln!(writer, "orientation\tbin_start\tbin_end\tcount\tcontact_probability")?;
+    for (orientation, histogram) in histograms {
+        for (start, end, count) in histogram.bins() {
+            let probability = count as f64 / histogram.total() as f64 / (end - start) as f64;
+            writeln!(writer, "{}\t{}\t{}\t{}\t{:.6e}", orientation, start, end, count, probability)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the <base>.insertSize.tsv report: insert-size histograms of the VI, DE, SC and RE pairs
+pub fn write_insert_size_report(histograms: &BTreeMap<InteractionType, Histogram>, output_dir: &Path,
+    base_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("{}.insertSize.tsv", base_name)))?);
+    writeln!(writer, "class\tbin_start\tbin_end\tcount")?;
+    for class in INSERT_SIZE_CLASSES {
+        if let Some(histogram) = histograms.get(&class) {
+            for (start, end, count) in histogram.bins() {
+                writeln!(writer, "{}\t{}\t{}\t{}", class, start, end, count)?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linear_bins() {
+        let histogram = Histogram::new(Binning::Linear(INSERT_SIZE_BIN));
+        assert_eq!(histogram.get_bin(0), 0);
+        assert_eq!(histogram.get_bin(9), 0);
+        assert_eq!(histogram.get_bin(10), 1);
+        assert_eq!(histogram.bin_start(1), 10);
+    }
+
+    #[test]
+    fn test_log_bin_boundaries() {
+        let histogram = Histogram::new(Binning::Log(CIS_BINS_PER_DECADE));
+        assert_eq!(histogram.get_bin(0), 0);
+        assert_eq!(histogram.get_bin(1), 0);
+        // Powers of ten start a bin, despite log10 rounding
+        for (value, bin) in [(10, 10), (100, 20), (1000, 30), (1_000_000, 60), (1_000_000_000, 90)] {
+            assert_eq!(histogram.get_bin(value - 1), bin - 1);
+            assert_eq!(histogram.get_bin(value), bin);
+            assert_eq!(histogram.bin_start(bin), value);
+        }
+        // Every value falls in the bin whose [start, end) interval contains it
+        for value in 1..100_000 {
+            let bin = histogram.get_bin(value);
+            assert!(histogram.bin_start(bin) <= value && value < histogram.bin_start(bin + 1));
+        }
+    }
+
+    #[test]
+    fn test_bins() {
+        let mut histogram = Histogram::new(Binning::Log(CIS_BINS_PER_DECADE));
+        histogram.add(1000);
+        histogram.add(1300);
+        let mut other = Histogram::new(Binning::Log(CIS_BINS_PER_DECADE));
+        other.add(1000);
+        histogram.merge(&other);
+        assert_eq!(histogram.total(), 3);
+        assert_eq!(histogram.bins().collect::<Vec<_>>(), [(1000, 1259, 2), (1259, 1585, 1)]);
+    }
+}
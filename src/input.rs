@@ -0,0 +1,369 @@
+//! Alignment input: BAM, CRAM and SAM files decoded as BAM records
+
+use log::warn;
+use noodles::{cram, fasta};
+use noodles_bam as bam;
+use noodles_bgzf as bgzf;
+use noodles_sam as sam;
+use noodles_sam::alignment::io::Write as _;
+use noodles_sam::alignment::record::Flags;
+use noodles_sam::alignment::RecordBuf;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::iter::Peekable;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+/// Path of the standard input
+pub const STDIN: &str = "-";
+
+/// Records decoded from a CRAM file and waiting to be classified
+const CRAM_CHANNEL_SIZE: usize = 4096;
+
+/// Alignment records of the input, in file order
+pub type AlignmentRecords = Box<dyn Iterator<Item = io::Result<bam::Record>>>;
+
+/// Format of an alignment file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentFormat {
+    Bam,
+    Cram,
+    Sam,
+}
+
+/// Alignment files listed in a file, one per line, blank lines and # comments being skipped
+pub fn read_input_list(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut inputs = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let input = line.trim();
+        if !input.is_empty() && !input.starts_with('#') {
+            inputs.push(input.to_string());
+        }
+    }
+    Ok(inputs)
+}
+
+/// Format of an alignment stream from its magic number (CRAM, BGZF for BAM, or else SAM), without consuming it
+pub fn detect_alignment_format<R: BufRead>(reader: &mut R) -> io::Result<AlignmentFormat> {
+    let magic = reader.fill_buf()?;
+    let format = if magic.starts_with(b"CRAM") {
+        AlignmentFormat::Cram
+    } else if magic.starts_with(&[0x1f, 0x8b]) {
+        AlignmentFormat::Bam
+    } else {
+        AlignmentFormat::Sam
+    };
+    Ok(format)
+}
+
+/**
+    Open an alignment file, whatever its format, returning its header and its
+    records as BAM records, i.e. what the classification works on.
+
+    path : BAM, CRAM or SAM file, '-' for stdin
+    reference : indexed FASTA of the reference, to decode CRAM files
+    threads : BGZF worker threads of a BAM file (> 1)
+ */
+pub fn open_alignment_reader(path: &str, reference: Option<&Path>, threads: usize)
+    -> Result<(sam::Header, AlignmentRecords), Box<dyn Error>> {
+    let mut input: Box<dyn BufRead + Send> = if path == STDIN {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
+    };
+    match detect_alignment_format(&mut input)? {
+        AlignmentFormat::Bam => {
+            let mut reader = open_bam_reader(input, threads);
+            let header = reader.read_header()?;
+            let records = std::iter::from_fn(move || {
+                let mut record = bam::Record::default();
+                match reader.read_record(&mut record) {
+                    Ok(0) => None,
+                    Ok(_) => Some(Ok(record)),
+                    Err(e) => Some(Err(e)),
+                }
+            });
+            Ok((header, Box::new(records)))
+        }
+        AlignmentFormat::Sam => {
+            let mut reader = sam::io::Reader::new(input);
+            let header = reader.read_header()?;
+            let mut encoder = BamEncoder::new(header.clone());
+            let records = std::iter::from_fn(move || {
+                let mut record = sam::Record::default();
+                match reader.read_record(&mut record) {
+                    Ok(0) => None,
+                    Ok(_) => Some(encoder.encode(&record)),
+                    Err(e) => Some(Err(e)),
+                }
+            });
+            Ok((header, Box::new(records)))
+        }
+        AlignmentFormat::Cram => open_cram_reader(input, path, reference),
+    }
+}
+
+/**
+    Open R1 and R2 files aligned separately as single-end reads and combine
+    them into paired records, as the HiC-Pro bowtie_combine step does. Both
+    files have to be sorted by read name in the same order, e.g. as the FASTQ
+    files, and share their reference sequences. The decompression threads are
+    shared by the two files.
+ */
+pub fn open_mate_reader(r1_path: &str, r2_path: &str, reference: Option<&Path>, threads: usize)
+    -> Result<(sam::Header, AlignmentRecords), Box<dyn Error>> {
+    let (header, r1_records) = open_alignment_reader(r1_path, reference, threads.div_ceil(2))?;
+    let (r2_header, r2_records) = open_alignment_reader(r2_path, reference, threads / 2)?;
+    if r2_header.reference_sequences() != header.reference_sequences() {
+        return Err(format!("Reference sequences of {} differ from those of {}", r2_path, r1_path).into());
+    }
+    let records = combine_mate_records(r1_records, r2_records, header.clone());
+    Ok((header, records))
+}
+
+/**
+    Walk the records of R1 and R2 in lockstep, one query name at a time, and
+    flag them as paired (first/last segment, mate strand and position from the
+    primary alignment of the other read). The /1 and /2 suffixes of the read
+    names are removed. R1 and R2 names not matching, or a read without primary
+    alignment, is an error.
+ */
+pub fn combine_mate_records(r1_records: AlignmentRecords, r2_records: AlignmentRecords,
+    header: sam::Header) -> AlignmentRecords {
+    let (mut r1_records, mut r2_records) = (r1_records.peekable(), r2_records.peekable());
+    let mut encoder = BamEncoder::new(header);
+    let mut pending: VecDeque<bam::Record> = VecDeque::new();
+    Box::new(std::iter::from_fn(move || {
+        if pending.is_empty() {
+            let r1_group = match read_name_group(&mut r1_records) {
+                Ok(group) => group,
+                Err(e) => return Some(Err(e)),
+            };
+            let r2_group = match read_name_group(&mut r2_records) {
+                Ok(group) => group,
+                Err(e) => return Some(Err(e)),
+            };
+            match combine_mates(&r1_group, &r2_group, &mut encoder) {
+                Ok(records) => pending.extend(records),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        pending.pop_front().map(Ok)
+    }))
+}
+
+/// Read name without its /1 or /2 suffix
+fn get_mate_name(record: &bam::Record) -> Option<&[u8]> {
+    record.name().map(|name| {
+        let name: &[u8] = name;
+        name.strip_suffix(b"/1").or_else(|| name.strip_suffix(b"/2")).unwrap_or(name)
+    })
+}
+
+/// Next records sharing a read name, empty at the end of the input
+fn read_name_group(records: &mut Peekable<AlignmentRecords>) -> io::Result<Vec<bam::Record>> {
+    let mut group: Vec<bam::Record> = Vec::new();
+    loop {
+        let same_name = match records.peek() {
+            None => break,
+            Some(Err(_)) => true,
+            Some(Ok(record)) => group.first().is_none_or(|first| get_mate_name(first) == get_mate_name(record)),
+        };
+        if !same_name {
+            break;
+        }
+        if let Some(result) = records.next() {
+            group.push(result?);
+        }
+    }
+    Ok(group)
+}
+
+/// Paired records of a read name, R1 records first
+fn combine_mates(r1_group: &[bam::Record], r2_group: &[bam::Record], encoder: &mut BamEncoder)
+    -> io::Result<Vec<bam::Record>> {
+    let (r1_name, r2_name) = (r1_group.first().and_then(get_mate_name), r2_group.first().and_then(get_mate_name));
+    if r1_group.is_empty() != r2_group.is_empty() || r1_name != r2_name {
+        let name = |name: Option<&[u8]>| name.map(|n| String::from_utf8_lossy(n).to_string()).unwrap_or_else(|| "end of file".to_string());
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("R1 and R2 reads are not in the same order: {} / {}", name(r1_name), name(r2_name))));
+    }
+    // Mate fields come from the primary alignment of the other read
+    let primary = |group: &'_ [bam::Record]| match group.first() {
+        None => Ok(None),
+        Some(first) => group.iter()
+            .find(|record| !record.flags().is_secondary() && !record.flags().is_supplementary())
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Read {} has no primary alignment",
+                first.name().map(|n| n.to_string()).unwrap_or_default()))),
+    };
+    let (r1_primary, r2_primary) = (primary(r1_group)?, primary(r2_group)?);
+
+    let mut records = Vec::with_capacity(r1_group.len() + r2_group.len());
+    for (group, segment, mate) in [
+        (r1_group, Flags::FIRST_SEGMENT, &r2_primary),
+        (r2_group, Flags::LAST_SEGMENT, &r1_primary),
+    ] {
+        for record in group {
+            let mut record_buf = RecordBuf::try_from_alignment_record(&encoder.header, record)?;
+            if let (Some(name), Some(mate_name)) = (record_buf.name_mut(), get_mate_name(record)) {
+                name.truncate(mate_name.len());
+            }
+            let flags = record_buf.flags_mut();
+            flags.remove(Flags::FIRST_SEGMENT | Flags::LAST_SEGMENT | Flags::PROPERLY_SEGMENTED);
+            flags.insert(Flags::SEGMENTED | segment);
+            if let Some(mate) = mate {
+                flags.set(Flags::MATE_UNMAPPED, mate.flags().is_unmapped());
+                flags.set(Flags::MATE_REVERSE_COMPLEMENTED, mate.flags().is_reverse_complemented());
+                *record_buf.mate_reference_sequence_id_mut() = mate.reference_sequence_id().transpose()?;
+                *record_buf.mate_alignment_start_mut() = mate.alignment_start().transpose()?;
+            }
+            *record_buf.template_length_mut() = 0;
+            records.push(encoder.encode(&record_buf)?);
+        }
+    }
+    Ok(records)
+}
+
+/// Open a BAM stream, BGZF blocks being decompressed on worker threads when threads > 1
+pub fn open_bam_reader<R: Read + Send + 'static>(input: R, threads: usize) -> bam::io::Reader<Box<dyn Read>> {
+    let decoder: Box<dyn Read> = match NonZeroUsize::new(threads).filter(|n| n.get() > 1) {
+        Some(worker_count) => Box::new(bgzf::io::MultithreadedReader::with_worker_count(worker_count, input)),
+        None => Box::new(bgzf::io::Reader::new(input)),
+    };
+    bam::io::Reader::from(decoder)
+}
+
+/**
+    Open a CRAM file, its records being decoded against the reference on a
+    separate thread. Without reference, the reference sequences have to be
+    embedded in the CRAM file.
+ */
+fn open_cram_reader(input: Box<dyn BufRead + Send>, path: &str, reference: Option<&Path>)
+    -> Result<(sam::Header, AlignmentRecords), Box<dyn Error>> {
+    let repository = match reference {
+        Some(reference) => {
+            let reader = fasta::io::indexed_reader::Builder::default().build_from_path(reference)?;
+            fasta::Repository::new(fasta::repository::adapters::IndexedReader::new(reader))
+        }
+        None => {
+            warn!("Warning: CRAM input without --reference, reference sequences must be embedded in {}", path);
+            fasta::Repository::default()
+        }
+    };
+    let mut reader = cram::io::reader::Builder::default()
+        .set_reference_sequence_repository(repository)
+        .build_from_reader(input);
+    let header = reader.read_header()?;
+
+    let (sender, receiver) = mpsc::sync_channel(CRAM_CHANNEL_SIZE);
+    let (record_header, mut encoder) = (header.clone(), BamEncoder::new(header.clone()));
+    thread::spawn(move || {
+        for result in reader.records(&record_header) {
+            let record = result.and_then(|record| encoder.encode(&record));
+            let failed = record.is_err();
+            // Stop decoding when the records are not consumed anymore or on error
+            if sender.send(record).is_err() || failed {
+                break;
+            }
+        }
+    });
+    Ok((header, Box::new(receiver.into_iter())))
+}
+
+/// Re-encoding of SAM and CRAM records as BAM records
+struct BamEncoder {
+    header: sam::Header,
+    writer: bam::io::Writer<Vec<u8>>,
+}
+
+impl BamEncoder {
+    fn new(header: sam::Header) -> Self {
+        BamEncoder { header, writer: bam::io::Writer::from(Vec::new()) }
+    }
+
+    fn encode(&mut self, record: &dyn sam::alignment::Record) -> io::Result<bam::Record> {
+        self.writer.get_mut().clear();
+        self.writer.write_alignment_record(&self.header, record)?;
+        let mut bam_record = bam::Record::default();
+        bam::io::Reader::from(self.writer.get_ref().as_slice()).read_record(&mut bam_record)?;
+        Ok(bam_record)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const HEADER: &str = "@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:chr2\tLN:10000\n";
+
+    /// BAM records and their encoder from SAM lines
+    fn get_records(lines: &[&str]) -> (Vec<bam::Record>, BamEncoder) {
+        let text = format!("{}{}\n", HEADER, lines.join("\n"));
+        let mut reader = sam::io::Reader::new(text.as_bytes());
+        let header = reader.read_header().unwrap();
+        let mut encoder = BamEncoder::new(header.clone());
+        let records = reader.records()
+            .map(|record| encoder.encode(&record.unwrap()).unwrap())
+            .collect();
+        (records, encoder)
+    }
+
+    /// BAM records from SAM lines, for the tests of other modules
+    pub(crate) fn encode_records(lines: &[&str]) -> Vec<bam::Record> {
+        get_records(lines).0
+    }
+
+    /// Header of the records of encode_records
+    pub(crate) fn get_header() -> sam::Header {
+        sam::io::Reader::new(HEADER.as_bytes()).read_header().unwrap()
+    }
+
+    #[test]
+    fn test_combine_mates() {
+        let (r1, mut encoder) = get_records(&[
+            "read1/1\t0\tchr1\t100\t60\t10M\t*\t0\t0\tACGTACGTAC\t*",
+            "read1/1\t2048\tchr1\t500\t60\t5S5M\t*\t0\t0\tACGTACGTAC\t*",
+        ]);
+        let (r2, _) = get_records(&["read1/2\t16\tchr1\t300\t60\t10M\t*\t0\t0\tACGTACGTAC\t*"]);
+        let records = combine_mates(&r1, &r2, &mut encoder).unwrap();
+        assert_eq!(records.len(), 3);
+        for record in &records {
+            let name: &[u8] = record.name().unwrap();
+            assert_eq!(name, b"read1");
+            assert!(record.flags().is_segmented());
+            assert_eq!(record.template_length(), 0);
+        }
+        // R1 records carry the R2 primary alignment as mate, and conversely
+        for record in &records[..2] {
+            assert!(record.flags().is_first_segment() && !record.flags().is_last_segment());
+            assert!(record.flags().is_mate_reverse_complemented());
+            assert_eq!(record.mate_alignment_start().transpose().unwrap().map(usize::from), Some(300));
+        }
+        assert!(records[1].flags().is_supplementary());
+        assert!(records[2].flags().is_last_segment() && !records[2].flags().is_first_segment());
+        assert!(!records[2].flags().is_mate_reverse_complemented());
+        assert_eq!(records[2].mate_reference_sequence_id().transpose().unwrap(), Some(0));
+        assert_eq!(records[2].mate_alignment_start().transpose().unwrap().map(usize::from), Some(100));
+    }
+
+    #[test]
+    fn test_combine_mates_errors() {
+        let (r1, mut encoder) = get_records(&["read1\t0\tchr1\t100\t60\t10M\t*\t0\t0\tACGTACGTAC\t*"]);
+        let (r2, _) = get_records(&["read2\t0\tchr1\t300\t60\t10M\t*\t0\t0\tACGTACGTAC\t*"]);
+        let error = combine_mates(&r1, &r2, &mut encoder).unwrap_err();
+        assert!(error.to_string().contains("not in the same order: read1 / read2"));
+        let error = combine_mates(&r1, &[], &mut encoder).unwrap_err();
+        assert!(error.to_string().contains("read1 / end of file"));
+
+        let (r2, _) = get_records(&["read1\t256\tchr1\t300\t0\t10M\t*\t0\t0\tACGTACGTAC\t*"]);
+        let error = combine_mates(&r1, &r2, &mut encoder).unwrap_err();
+        assert_eq!(error.to_string(), "Read read1 has no primary alignment");
+    }
+}
@@ -0,0 +1,63 @@
+//! HiC-Pro style classification of Hi-C read pairs into valid interactions,
+//! dangling ends, religations, self circles, single-end and filtered pairs.
+//!
+//! - [`input`]: BAM, CRAM and SAM input decoded as BAM records
+//! - [`fragments`]: restriction fragment loading and overlap index
+//! - [`reads`]: positions, strands and tags of BAM records
+//! - [`quality`]: per-mate MAPQ, flag, soft-clip and edit distance filters
+//! - [`classify`]: pair classification, independent of any output
+//! - [`output`]: validPairs, .pairs, BAM, matrix and .hic writers
+//! - [`stats`]: classification counters and the `.RSstat` report
+//! - [`histogram`]: cis-distance (P(s)) and insert-size histograms
+//! - [`report`]: JSON and MultiQC reports of the statistics
+//! - [`pipeline`]: batched, optionally multithreaded, processing of query name groups
+//! - [`run`]: a whole run, from the inputs to the outputs and reports, as the command line does
+//!
+//! A minimal streaming use, classifying the records of one query name:
+//!
+//! ```no_run
+//! use hic2frag::classify::{classify_read_group, ClassifiedGroup, ClassifyOptions};
+//! use hic2frag::fragments::{convert_vec_to_lapper, load_restriction_fragments};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let fragments = load_restriction_fragments(&["DpnII.bed".to_string()])?;
+//! let index = convert_vec_to_lapper(&fragments);
+//! let options = ClassifyOptions::default();
+//!
+//! let mut reader = noodles_bam::io::reader::Builder::default().build_from_path("sample.bam")?;
+//! let header = reader.read_header()?;
+//! let mut group = Vec::new();
+//! for result in reader.records() {
+//!     let record = result?;
+//!     if group.first().is_some_and(|first: &noodles_bam::Record| first.name() != record.name()) {
+//!         if let ClassifiedGroup::Pair { pair, .. } = classify_read_group(std::mem::take(&mut group), &header, &index, &options)? {
+//!             println!("{}", pair.interaction_type);
+//!         }
+//!     }
+//!     group.push(record);
+//! }
+//! // The last query name
+//! if let ClassifiedGroup::Pair { pair, .. } = classify_read_group(group, &header, &index, &options)? {
+//!     println!("{}", pair.interaction_type);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod classify;
+#[cfg(feature = "cool")]
+pub mod cool;
+pub mod dedup;
+pub mod digest;
+pub mod fragments;
+pub mod hic;
+pub mod histogram;
+pub mod input;
+pub mod matrix;
+pub mod output;
+pub mod pipeline;
+pub mod quality;
+pub mod reads;
+pub mod report;
+pub mod run;
+pub mod stats;
@@ -0,0 +1,130 @@
+//! Binned contact matrices (HiC-Pro sparse format)
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Genome-wide binned contact counts at one resolution, HiC-Pro build_matrix style
+#[derive(Debug)]
+pub struct ContactMatrix {
+    pub resolution: u64,
+    pub chroms: Vec<(String, u64)>,
+    // First genome-wide bin of each chromosome
+    offsets: Vec<u64>,
+    counts: HashMap<(u64, u64), u64>,
+}
+
+impl ContactMatrix {
+    /**
+        resolution : bin size in bp
+        chroms : chromosome names and sizes, in the BAM header order
+     */
+    pub fn new(resolution: u64, chroms: &[(String, u64)]) -> Self {
+        let mut offsets = Vec::with_capacity(chroms.len());
+        let mut n_bins = 0;
+        for (_, len) in chroms {
+            offsets.push(n_bins);
+            n_bins += len.div_ceil(resolution);
+        }
+        ContactMatrix { resolution, chroms: chroms.to_vec(), offsets, counts: HashMap::new() }
+    }
+
+    /// Genome-wide 0-based bin of a 1-based position on a chromosome (by reference id)
+    pub fn get_bin(&self, tid: usize, pos: u64) -> Option<u64> {
+        let (_, len) = self.chroms.get(tid)?;
+        let pos0 = pos.saturating_sub(1).min(len.saturating_sub(1));
+        Some(self.offsets[tid] + pos0 / self.resolution)
+    }
+
+    /// Add a contact between two 1-based positions, stored in the upper triangle
+    pub fn add_contact(&mut self, tid1: usize, pos1: u64, tid2: usize, pos2: u64) {
+        if let (Some(bin1), Some(bin2)) = (self.get_bin(tid1, pos1), self.get_bin(tid2, pos2)) {
+            let key = (bin1.min(bin2), bin1.max(bin2));
+            *self.counts.entry(key).or_default() += 1;
+        }
+    }
+
+    /// Bins as (chrom index, start, end), genome-wide ordered
+    pub fn bins(&self) -> impl Iterator<Item = (usize, u64, u64)> + '_ {
+        let resolution = self.resolution;
+        self.chroms.iter().enumerate().flat_map(move |(tid, (_, len))| {
+            (0..len.div_ceil(resolution))
+                .map(move |i| (tid, i * resolution, ((i + 1) * resolution).min(*len)))
+        })
+    }
+
+    /// Non-zero pixels (bin1, bin2, count) with bin1 <= bin2, sorted by bin1 then bin2
+    pub fn pixels(&self) -> Vec<(u64, u64, u64)> {
+        let mut pixels: Vec<(u64, u64, u64)> = self.counts.iter()
+            .map(|(&(bin1, bin2), &count)| (bin1, bin2, count))
+            .collect();
+        pixels.sort_unstable();
+        pixels
+    }
+
+    /// First genome-wide bin of each chromosome
+    pub fn chrom_offsets(&self) -> &[u64] {
+        &self.offsets
+    }
+
+    /// Chromosome index and bin on that chromosome of a genome-wide bin
+    pub fn get_chrom_bin(&self, bin: u64) -> (usize, u64) {
+        let tid = self.offsets.partition_point(|&offset| offset <= bin).saturating_sub(1);
+        (tid, bin - self.offsets[tid])
+    }
+
+    /// Same contacts at a resolution `factor` times coarser
+    pub fn coarsen(&self, factor: u64) -> ContactMatrix {
+        let mut coarse = ContactMatrix::new(self.resolution * factor, &self.chroms);
+        for (&(bin1, bin2), &count) in &self.counts {
+            let (tid1, chrom_bin1) = self.get_chrom_bin(bin1);
+            let (tid2, chrom_bin2) = self.get_chrom_bin(bin2);
+            let offsets = coarse.chrom_offsets();
+            let key = (offsets[tid1] + chrom_bin1 / factor, offsets[tid2] + chrom_bin2 / factor);
+            *coarse.counts.entry(key).or_default() += count;
+        }
+        coarse
+    }
+
+    /**
+        Zoom levels at 2^k times the resolution, until the longest chromosome
+        fits into a single bin, as cooler zoomify does
+     */
+    pub fn zoom_pyramid(&self) -> Vec<ContactMatrix> {
+        let max_len = self.chroms.iter().map(|(_, len)| *len).max().unwrap_or(0);
+        let mut levels = Vec::new();
+        let mut factor = 2;
+        while self.resolution * factor / 2 < max_len {
+            levels.push(self.coarsen(factor));
+            factor *= 2;
+        }
+        levels
+    }
+
+    pub fn n_bins(&self) -> u64 {
+        self.chroms.iter().map(|(_, len)| len.div_ceil(self.resolution)).sum()
+    }
+
+    /**
+        Write the HiC-Pro sparse matrix pair: `<base>_<res>_abs.bed` with 1-based
+        bin ids and `<base>_<res>.matrix` with "bin_i bin_j count" upper triangle
+     */
+    pub fn write_hicpro(&self, output_dir: &Path, base_name: &str) -> Result<(), Box<dyn Error>> {
+        let bed_file = output_dir.join(format!("{}_{}_abs.bed", base_name, self.resolution));
+        let mut bed_writer = BufWriter::new(File::create(bed_file)?);
+        for (id, (tid, start, end)) in self.bins().enumerate() {
+            writeln!(bed_writer, "{}\t{}\t{}\t{}", self.chroms[tid].0, start, end, id + 1)?;
+        }
+        bed_writer.flush()?;
+
+        let matrix_file = output_dir.join(format!("{}_{}.matrix", base_name, self.resolution));
+        let mut matrix_writer = BufWriter::new(File::create(matrix_file)?);
+        for (bin1, bin2, count) in self.pixels() {
+            writeln!(matrix_writer, "{}\t{}\t{}", bin1 + 1, bin2 + 1, count)?;
+        }
+        matrix_writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,632 @@
+//! Writers of the classified pairs (validPairs, .pairs, BAM, matrices, .hic)
+
+#[cfg(feature = "cool")]
+use crate::cool;
+use crate::classify::{get_ligation_junction, get_valid_orientation, ClassifiedPair, InteractionType, Orientation};
+use crate::dedup;
+use crate::hic;
+use crate::matrix;
+use crate::quality::get_mate_type;
+use crate::reads::*;
+use crate::stats::{update_allele_statistics, update_contact_statistics, update_distance_statistics, update_multimap_statistics, Statistics};
+use bed_utils::bed::{BEDLike, BED};
+use noodles_bam as bam;
+use noodles_bgzf as bgzf;
+use noodles_sam as sam;
+use noodles_sam::alignment::io::Write as _;
+use noodles_sam::alignment::record::data::field::Tag;
+use noodles_sam::alignment::record_buf::data::field::Value as BufValue;
+use noodles_sam::alignment::RecordBuf;
+use noodles_sam::header::record::value::{map::{program::tag as program_tag, Program}, Map};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// Writers of the classified pairs and accumulated contact maps
+pub struct OutputHandlers {
+    pub valid: Box<dyn Write>,
+    pub de: Option<BufWriter<File>>,
+    pub re: Option<BufWriter<File>>,
+    pub sc: Option<BufWriter<File>>,
+    pub dump: Option<BufWriter<File>>,
+    pub single: Option<BufWriter<File>>,
+    pub filt: Option<BufWriter<File>>,
+    pub lowq: Option<BufWriter<File>>,
+    pub sam: Option<InteractionBamWriter>,
+    pub pairs: Option<Box<dyn Write>>,
+    pub matrices: Vec<matrix::ContactMatrix>,
+    pub hic: Option<hic::HicFile>,
+    pub duplicates: Option<DuplicateOutput>,
+    /// Type multi-mapped mates as "M" in the .pairs output
+    pub label_multimapped: bool,
+}
+
+/// Duplicate detection of valid pairs, duplicates being written to .dupPairs
+pub struct DuplicateOutput {
+    pub filter: dedup::DuplicateFilter,
+    pub writer: BufWriter<File>,
+}
+
+/// BAM output of all processed reads, tagged with their pair classification
+pub struct InteractionBamWriter {
+    pub writer: bam::io::Writer<bgzf::io::Writer<File>>,
+    pub header: sam::Header,
+}
+
+/**
+    Open the validPairs output and, as requested, the other classes (all_output),
+    the classified BAM, the .pairs file (pairs_output: Some(bgzip)) and the
+    contact matrices at the given resolutions. With to_stdout, the valid pairs
+    are written to stdout instead, as .pairs if requested or else as validPairs.
+ */
+pub fn create_output_handlers(output_dir: &PathBuf, base_name: &str, all_output: bool, sam_output: bool,
+    pairs_output: Option<bool>, to_stdout: bool, matrix_resolutions: &[u64], header: &sam::Header)
+    -> Result<OutputHandlers, Box<dyn Error>> {
+    let valid: Box<dyn Write> = if to_stdout && pairs_output.is_none() {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        let valid_file = output_dir.join(format!("{}.validPairs", base_name));
+        Box::new(BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(valid_file)?))
+    };
+
+    let de = if all_output {
+        let de_file = output_dir.join(format!("{}.DEPairs", base_name));
+        Some(BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(de_file)?))
+    } else {
+        None
+    };
+
+    let re = if all_output {
+        let re_file = output_dir.join(format!("{}.REPairs", base_name));
+        Some(BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(re_file)?))
+    } else {
+        None
+    };
+
+    let sc = if all_output {
+        let sc_file = output_dir.join(format!("{}.SCPairs", base_name));
+        Some(BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(sc_file)?))
+    } else {
+        None
+    };
+
+    let dump = if all_output {
+        let dump_file = output_dir.join(format!("{}.DumpPairs", base_name));
+        Some(BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(dump_file)?))
+    } else {
+        None
+    };
+
+    let single = if all_output {
+        let single_file = output_dir.join(format!("{}.SinglePairs", base_name));
+        Some(BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(single_file)?))
+    } else {
+        None
+    };
+
+    let filt = if all_output {
+        let filt_file = output_dir.join(format!("{}.FiltPairs", base_name));
+        Some(BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(filt_file)?))
+    } else {
+        None
+    };
+
+    let lowq = if all_output {
+        let lowq_file = output_dir.join(format!("{}.LowQualPairs", base_name));
+        Some(BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(lowq_file)?))
+    } else {
+        None
+    };
+
+    let sam = if sam_output {
+        let sam_file = output_dir.join(format!("{}_interaction.bam", base_name));
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(sam_file)?;
+        Some(create_interaction_bam_writer(file, header)?)
+    } else {
+        None
+    };
+
+    let pairs = match pairs_output {
+        Some(bgzip) => {
+            let sink: Box<dyn Write> = if to_stdout {
+                Box::new(io::stdout())
+            } else {
+                let extension = if bgzip { "pairs.gz" } else { "pairs" };
+                let pairs_file = output_dir.join(format!("{}.{}", base_name, extension));
+                Box::new(OpenOptions::new().create(true).write(true).truncate(true).open(pairs_file)?)
+            };
+            let mut writer: Box<dyn Write> = if bgzip {
+                Box::new(bgzf::io::Writer::new(sink))
+            } else {
+                Box::new(BufWriter::new(sink))
+            };
+            write_pairs_header(&mut writer, header)?;
+            Some(writer)
+        }
+        None => None,
+    };
+
+    let chrom_sizes = get_chrom_sizes(header);
+    let matrices = matrix_resolutions.iter()
+        .map(|&resolution| matrix::ContactMatrix::new(resolution, &chrom_sizes))
+        .collect();
+
+    Ok(OutputHandlers {
+        valid,
+        de,
+        re,
+        sc,
+        dump,
+        single,
+        filt,
+        lowq,
+        sam,
+        pairs,
+        matrices,
+        hic: None,
+        duplicates: None,
+        label_multimapped: false,
+    })
+}
+
+/**
+    Open the classified BAM output, using the input header with an additional
+    @PG line for hic2frag
+ */
+pub fn create_interaction_bam_writer(file: File, header: &sam::Header) -> Result<InteractionBamWriter, Box<dyn Error>> {
+    let mut header = header.clone();
+    let command_line = std::env::args().collect::<Vec<_>>().join(" ");
+    let program = Map::<Program>::builder()
+        .insert(program_tag::NAME, "hic2frag")
+        .insert(program_tag::VERSION, env!("CARGO_PKG_VERSION"))
+        .insert(program_tag::COMMAND_LINE, command_line)
+        .build()?;
+    header.programs_mut().add("hic2frag", program)?;
+
+    let mut writer = bam::io::Writer::new(file);
+    writer.write_header(&header)?;
+    Ok(InteractionBamWriter { writer, header })
+}
+
+/// Write a read with its pair classification in the CT:Z tag (VI/DE/RE/SC/SI/FILT/DUMP)
+pub fn write_interaction_record(
+    bam_writer: &mut InteractionBamWriter,
+    read: &bam::Record,
+    interaction_type: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut record = RecordBuf::try_from_alignment_record(&bam_writer.header, read)?;
+    record.data_mut().insert(Tag::COMPLETE_READ_ANNOTATIONS, BufValue::from(interaction_type));
+    bam_writer.writer.write_alignment_record(&bam_writer.header, &record)?;
+    Ok(())
+}
+
+/// Chromosome names and sizes, in the BAM header order
+pub fn get_chrom_sizes(header: &sam::Header) -> Vec<(String, u64)> {
+    header.reference_sequences().iter()
+        .map(|(name, reference_sequence)| (name.to_string(), reference_sequence.length().get() as u64))
+        .collect()
+}
+
+/// Write <base>_<res>.cool and, with zoom levels, <base>.mcool
+#[cfg(feature = "cool")]
+pub fn write_cool_output(contact_matrix: &matrix::ContactMatrix, zoom_levels: &[matrix::ContactMatrix],
+    output_dir: &Path, base_name: &str, mcool: bool) -> Result<(), Box<dyn Error>> {
+    let cool_file = output_dir.join(format!("{}_{}.cool", base_name, contact_matrix.resolution));
+    cool::write_cool(&cool_file, contact_matrix)?;
+    if mcool {
+        let levels: Vec<&matrix::ContactMatrix> = std::iter::once(contact_matrix)
+            .chain(zoom_levels.iter())
+            .collect();
+        cool::write_mcool(&output_dir.join(format!("{}.mcool", base_name)), &levels)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "cool"))]
+pub fn write_cool_output(_contact_matrix: &matrix::ContactMatrix, _zoom_levels: &[matrix::ContactMatrix],
+    _output_dir: &Path, _base_name: &str, _mcool: bool) -> Result<(), Box<dyn Error>> {
+    Err("hic2frag was built without .cool support, rebuild with --features cool".into())
+}
+
+/// Add a valid pair to the binned contact matrices and .hic, using the 5' position of each read
+pub fn add_matrix_contact(handlers: &mut OutputHandlers, read1: &bam::Record, read2: &bam::Record) {
+    let tid1 = read1.reference_sequence_id().transpose().ok().flatten();
+    let tid2 = read2.reference_sequence_id().transpose().ok().flatten();
+    let pos1 = get_read_pos(read1, "start");
+    let pos2 = get_read_pos(read2, "start");
+    if let (Some(tid1), Some(tid2), Some(pos1), Some(pos2)) = (tid1, tid2, pos1, pos2) {
+        for contact_matrix in handlers.matrices.iter_mut() {
+            contact_matrix.add_contact(tid1, pos1 as u64, tid2, pos2 as u64);
+        }
+        if let Some(ref mut hic_file) = handlers.hic {
+            hic_file.add_contact(tid1, pos1 as u64, tid2, pos2 as u64);
+        }
+    }
+}
+
+/// Whether a valid pair duplicates an already seen one, on the 5' position and strand of both reads
+pub fn is_duplicate_pair(filter: &mut dedup::DuplicateFilter, read1: &bam::Record, read2: &bam::Record) -> bool {
+    let Some((or1, or2)) = get_ordered_reads(read1, read2) else {
+        return false;
+    };
+    let tid1 = or1.reference_sequence_id().transpose().ok().flatten();
+    let tid2 = or2.reference_sequence_id().transpose().ok().flatten();
+    let pos1 = get_read_pos(or1, "start");
+    let pos2 = get_read_pos(or2, "start");
+    match (tid1, tid2, pos1, pos2) {
+        (Some(tid1), Some(tid2), Some(pos1), Some(pos2)) => filter.is_duplicate(
+            tid1, pos1 as u64, or1.flags().is_reverse_complemented(),
+            tid2, pos2 as u64, or2.flags().is_reverse_complemented()),
+        _ => false,
+    }
+}
+
+/// Header of the 4DN .pairs format, chromosome sizes being taken from the BAM header
+pub fn write_pairs_header(handler: &mut dyn Write, header: &sam::Header) -> Result<(), Box<dyn Error>> {
+    writeln!(handler, "## pairs format v1.0")?;
+    writeln!(handler, "#sorted: none")?;
+    writeln!(handler, "#shape: upper triangle")?;
+    writeln!(handler, "#genome_assembly: unknown")?;
+    for (name, reference_sequence) in header.reference_sequences() {
+        writeln!(handler, "#chromsize: {} {}", name, reference_sequence.length())?;
+    }
+    writeln!(handler, "#columns: readID chrom1 pos1 chrom2 pos2 strand1 strand2 pair_type frag1 frag2 mapq1 mapq2")?;
+    Ok(())
+}
+
+/**
+    Write a valid pair in the 4DN .pairs format, reads being ordered as in the
+    validPairs output (upper triangle). The pair_type is made of the pairtools
+    codes of the mates (U or M), given in R1, R2 order.
+ */
+pub fn write_pairs_record(
+    handler: &mut dyn Write,
+    r1: &bam::Record,
+    r2: &bam::Record,
+    r1_chrom: Option<&str>,
+    r2_chrom: Option<&str>,
+    r1_resfrag: Option<&BED<6>>,
+    r2_resfrag: Option<&BED<6>>,
+    mate_types: (char, char),
+) -> Result<(), Box<dyn Error>> {
+    let Some((or1, or2)) = get_ordered_reads(r1, r2) else {
+        return Ok(());
+    };
+    let (or1_chrom, or2_chrom, or1_resfrag, or2_resfrag, (or1_type, or2_type)) = if ptr::eq(or1, r1) {
+        (r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, mate_types)
+    } else {
+        (r2_chrom, r1_chrom, r2_resfrag, r1_resfrag, (mate_types.1, mate_types.0))
+    };
+    let fragname = |frag: Option<&BED<6>>| frag.and_then(|f| f.name()).unwrap_or(".").to_string();
+    writeln!(
+        handler,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}{}\t{}\t{}\t{}\t{}",
+        or1.name().map(|n| n.to_string()).unwrap_or_else(|| "Unknown".to_string()),
+        or1_chrom.unwrap_or("!"),
+        get_read_pos(or1, "start").unwrap_or(0),
+        or2_chrom.unwrap_or("!"),
+        get_read_pos(or2, "start").unwrap_or(0),
+        get_read_strand(or1),
+        get_read_strand(or2),
+        or1_type,
+        or2_type,
+        fragname(or1_resfrag),
+        fragname(or2_resfrag),
+        or1.mapping_quality().map(|q| q.get()).unwrap_or(0),
+        or2.mapping_quality().map(|q| q.get()).unwrap_or(0),
+    )?;
+    Ok(())
+}
+
+fn write_valid_pair(
+    handler: &mut dyn Write,
+    read1: &bam::Record,
+    _read2: &bam::Record,
+    r1_chrom: &str,
+    r2_chrom: &str,
+    r1_pos: usize,
+    r2_pos: usize,
+    r1_strand: &str,
+    r2_strand: &str,
+    dist: Option<u64>,
+    r1_fragname: &str,
+    r2_fragname: &str,
+    r1_mapq: u8,
+    r2_mapq: u8,
+    htag: &str,
+    reason: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    write!(
+        handler,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        read1.name().map(|n| n.to_string()).unwrap_or_else(|| "Unknown".to_string()),
+        r1_chrom,
+        r1_pos, 
+        r1_strand,
+        r2_chrom,
+        r2_pos, 
+        r2_strand,
+        dist.map_or_else(|| "*".to_string(), |d| d.to_string()),
+        r1_fragname,
+        r2_fragname,
+        r1_mapq,
+        r2_mapq,
+        htag
+    )?;
+    match reason {
+        Some(reason) => writeln!(handler, "\t{}", reason)?,
+        None => writeln!(handler)?,
+    }
+    Ok(())
+}
+
+fn write_single_pair(
+    handler: &mut dyn Write,
+    read: &bam::Record,
+    chrom: &str,
+    pos: usize,
+    strand: &str,
+    fragname: &str,
+    mapq: u8,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        handler,
+        "{}\t{}\t{}\t{}\t*\t*\t*\t*\t{}\t*\t{}\t*",
+        read.name().map(|n| n.to_string()).unwrap_or_else(|| "Unknown".to_string()),
+        chrom,
+        pos + 1, // Convert to 1-based
+        strand,
+        fragname,
+        mapq
+    )?;
+    Ok(())
+}
+
+/// Update the statistics and write the outputs of a classified read pair
+pub fn process_read_pair(
+    pair: &ClassifiedPair,
+    handlers: &mut OutputHandlers,
+    stats: &mut Statistics,
+    gtag: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let (r1, r2) = (&pair.r1, &pair.r2);
+    let (r1_chrom, r2_chrom) = (pair.r1_chrom.as_deref(), pair.r2_chrom.as_deref());
+    let (r1_resfrag, r2_resfrag) = (pair.r1_resfrag.as_ref(), pair.r2_resfrag.as_ref());
+    let (final_interaction_type, dist) = (pair.interaction_type, pair.dist);
+    
+    update_multimap_statistics(stats, pair);
+
+    // Update statistics and write output
+    match final_interaction_type {
+        InteractionType::Valid => {
+            stats.valid_counter += 1;
+            match get_valid_orientation(r1, r2) {
+                Some(Orientation::FF) => stats.valid_counter_ff += 1,
+                Some(Orientation::RR) => stats.valid_counter_rr += 1,
+                Some(Orientation::FR) => stats.valid_counter_fr += 1,
+                Some(Orientation::RF) => stats.valid_counter_rf += 1,
+                None => {}
+            }
+            
+            // Handle allele specific counting if gtag is provided
+            if let (Some(gtag), Some((or1, or2))) = (gtag, get_ordered_reads(r1, r2)) {
+                update_allele_statistics(stats, or1, or2, gtag);
+            }
+            
+            if let Some(junction) = r1_resfrag.zip(r2_resfrag)
+                .and_then(|(f1, f2)| get_ligation_junction(r1, f1, r2, f2)) {
+                *stats.ligation_junctions.entry(junction).or_default() += 1;
+            }
+            
+            if let Some(ref mut duplicates) = handlers.duplicates
+                && is_duplicate_pair(&mut duplicates.filter, r1, r2) {
+                stats.duplicate_counter += 1;
+                write_output_pair(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag,
+                                dist, &mut duplicates.writer, gtag, None)?;
+            } else {
+                write_output_pair(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, 
+                                dist, &mut handlers.valid, gtag, None)?;
+                update_distance_statistics(stats, pair);
+                update_contact_statistics(stats, r1, r2);
+                if let Some(ref mut handler) = handlers.pairs {
+                    let mate_types = if handlers.label_multimapped {
+                        (get_mate_type(pair.r1_multimap), get_mate_type(pair.r2_multimap))
+                    } else {
+                        ('U', 'U')
+                    };
+                    write_pairs_record(handler.as_mut(), r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, mate_types)?;
+                }
+                add_matrix_contact(handlers, r1, r2);
+            }
+        }
+        InteractionType::DanglingEnd => {
+            stats.de_counter += 1;
+            update_distance_statistics(stats, pair);
+            if let Some(ref mut handler) = handlers.de {
+                write_output_pair(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, 
+                                dist, handler, gtag, None)?;
+            }
+        }
+        InteractionType::Religation => {
+            stats.re_counter += 1;
+            update_distance_statistics(stats, pair);
+            if let Some(ref mut handler) = handlers.re {
+                write_output_pair(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, 
+                                dist, handler, gtag, None)?;
+            }
+        }
+        InteractionType::SelfCircle => {
+            stats.sc_counter += 1;
+            update_distance_statistics(stats, pair);
+            if let Some(ref mut handler) = handlers.sc {
+                write_output_pair(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, 
+                                dist, handler, gtag, None)?;
+            }
+        }
+        InteractionType::SingleEnd => {
+            stats.single_counter += 1;
+            if let Some(ref mut handler) = handlers.single {
+                write_single_output(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, handler)?;
+            }
+        }
+        InteractionType::Filtered(reason) => {
+            stats.filt_counter += 1;
+            *stats.filt_reasons.entry(reason).or_default() += 1;
+            if let Some(ref mut handler) = handlers.filt {
+                write_output_pair(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, 
+                                dist, handler, gtag, Some(&reason.to_string()))?;
+            }
+        }
+        InteractionType::Dumped(reason) => {
+            stats.dump_counter += 1;
+            *stats.dump_reasons.entry(reason).or_default() += 1;
+            if let Some(ref mut handler) = handlers.dump {
+                write_output_pair(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag, 
+                                dist, handler, gtag, Some(&reason.to_string()))?;
+            }
+        }
+        InteractionType::LowQuality(reason) => {
+            stats.lowq_counter += 1;
+            *stats.lowq_reasons.entry(reason).or_default() += 1;
+            if let Some(ref mut handler) = handlers.lowq {
+                write_output_pair(r1, r2, r1_chrom, r2_chrom, r1_resfrag, r2_resfrag,
+                                dist, handler, gtag, Some(&reason.to_string()))?;
+            }
+        }
+    }
+
+    if let Some(ref mut bam_writer) = handlers.sam {
+        let ct = final_interaction_type.code();
+        for read in [r1, r2].into_iter().chain(&pair.other_records) {
+            write_interaction_record(bam_writer, read, ct)?;
+        }
+    }
+
+        Ok(())
+}
+
+
+fn write_output_pair(
+    r1: &bam::Record,
+    r2: &bam::Record,
+    r1_chrom: Option<&str>,
+    r2_chrom: Option<&str>,
+    r1_resfrag: Option<&BED<6>>,
+    r2_resfrag: Option<&BED<6>>,
+    dist: Option<u64>,
+    handler: &mut dyn Write,
+    gtag: Option<&str>,
+    reason: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    if !r1.flags().is_unmapped() && !r2.flags().is_unmapped() {
+        // Get ordered reads
+        if let Some((or1, or2)) = get_ordered_reads(r1, r2) {
+            let or1_chrom = if ptr::eq(or1, r1) { r1_chrom } else { r2_chrom };
+            let or2_chrom = if ptr::eq(or1, r1) { r2_chrom } else { r1_chrom };
+            
+            let or1_resfrag = if ptr::eq(or1, r1) { r1_resfrag } else { r2_resfrag };
+            let or2_resfrag = if ptr::eq(or1, r1) { r2_resfrag } else { r1_resfrag };
+            
+            let or1_pos = get_read_pos(or1, "start").unwrap_or(0);
+            let or2_pos = get_read_pos(or2, "start").unwrap_or(0);
+            let or1_strand = get_read_strand(or1);
+            let or2_strand = get_read_strand(or2);
+            
+            let or1_fragname = or1_resfrag.map(|f| f.name().unwrap().to_string()).unwrap_or_else(|| "None".to_string());
+            let or2_fragname = or2_resfrag.map(|f| f.name().unwrap().to_string()).unwrap_or_else(|| "None".to_string());
+            
+            let htag = gtag.map(|tag| get_allele_tag(or1, or2, tag)).unwrap_or_default();
+            
+            write_valid_pair(
+                handler,
+                or1, or2,
+                or1_chrom.unwrap_or("*"),
+                or2_chrom.unwrap_or("*"),
+                or1_pos, or2_pos,
+                or1_strand, or2_strand,
+                dist,
+                &or1_fragname, &or2_fragname,
+                or1.mapping_quality().map(|q| q.get()).unwrap_or(0),
+                or2.mapping_quality().map(|q| q.get()).unwrap_or(0),
+                &htag,
+                reason,
+            )?;
+        }
+    } else if r2.flags().is_unmapped() && !r1.flags().is_unmapped() {
+        let r1_pos = get_read_pos(r1, "start").unwrap_or(0);
+        let r1_strand = get_read_strand(r1);
+        let r1_fragname = r1_resfrag.map(|f| f.chrom().to_string()).unwrap_or_else(|| "None".to_string());
+        
+        write_single_pair(
+            handler,
+            r1,
+            r1_chrom.unwrap_or("*"),
+            r1_pos,
+            r1_strand,
+            &r1_fragname,
+            r1.mapping_quality().map(|q| q.get()).unwrap_or(0),
+        )?;
+    } else if r1.flags().is_unmapped() && !r2.flags().is_unmapped() {
+        let r2_pos = get_read_pos(r2, "start").unwrap_or(0);
+        let r2_strand = get_read_strand(r2);
+        let r2_fragname = r2_resfrag.map(|f| f.chrom().to_string()).unwrap_or_else(|| "None".to_string());
+        
+        write_single_pair(
+            handler,
+            r2,
+            r2_chrom.unwrap_or("*"),
+            r2_pos,
+            r2_strand,
+            &r2_fragname,
+            r2.mapping_quality().map(|q| q.get()).unwrap_or(0),
+        )?;
+    }
+    
+    Ok(())
+}
+
+fn write_single_output(
+    r1: &bam::Record,
+    r2: &bam::Record,
+    r1_chrom: Option<&str>,
+    r2_chrom: Option<&str>,
+    r1_resfrag: Option<&BED<6>>,
+    r2_resfrag: Option<&BED<6>>,
+    handler: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    if !r1.flags().is_unmapped() {
+        let r1_pos = get_read_pos(r1, "start").unwrap_or(0);
+        let r1_strand = get_read_strand(r1);
+        let r1_fragname = r1_resfrag.map(|f| f.chrom().to_string()).unwrap_or_else(|| "None".to_string());
+        
+        write_single_pair(
+            handler,
+            r1,
+            r1_chrom.unwrap_or("*"),
+            r1_pos,
+            r1_strand,
+            &r1_fragname,
+            r1.mapping_quality().map(|q| q.get()).unwrap_or(0),
+        )?;
+    }
+    
+    if !r2.flags().is_unmapped() {
+        let r2_pos = get_read_pos(r2, "start").unwrap_or(0);
+        let r2_strand = get_read_strand(r2);
+        let r2_fragname = r2_resfrag.map(|f| f.chrom().to_string()).unwrap_or_else(|| "None".to_string());
+        
+        write_single_pair(
+            handler,
+            r2,
+            r2_chrom.unwrap_or("*"),
+            r2_pos,
+            r2_strand,
+            &r2_fragname,
+            r2.mapping_quality().map(|q| q.get()).unwrap_or(0),
+        )?;
+    }
+
+        Ok(())
+}
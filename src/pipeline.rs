@@ -0,0 +1,205 @@
+//! Batched, optionally multithreaded, processing of query name groups
+
+use crate::classify::{classify_read_group, ClassifiedGroup, ClassifyOptions};
+use crate::fragments::FragmentIndex;
+use crate::input::AlignmentRecords;
+use crate::output::{process_read_pair, OutputHandlers};
+use crate::reads::get_split_alignment_mapqs;
+use crate::stats::Statistics;
+use log::{info, warn};
+use noodles_bam as bam;
+use noodles_sam as sam;
+use noodles_sam::header::record::value::map::header::{sort_order, tag as header_tag};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+
+/// Number of query name groups classified together on the worker threads
+pub const BATCH_SIZE: usize = 10000;
+
+/// Number of the latest query names missing a mate checked for reappearance in grouped inputs
+pub const ORPHAN_NAME_WINDOW: usize = 100000;
+
+/**
+    Split a thread budget between the BGZF decompression workers and the
+    classification pool, so that --threads N keeps about N threads busy:
+    a quarter decompresses (on the main thread below 8 threads, which also
+    reads, groups and writes the records) and the rest classifies. CRAM input
+    is decoded on a single thread of its own instead.
+    Return (decompression threads, classification threads).
+ */
+pub fn split_threads(threads: usize) -> (usize, usize) {
+    let decompression = threads / 4;
+    (decompression.max(1), (threads - decompression).max(1))
+}
+
+/// Whether the header declares a coordinate-sorted file (@HD SO:coordinate)
+pub fn is_coordinate_sorted(header: &sam::Header) -> bool {
+    header.header()
+        .and_then(|hd| hd.other_fields().get(&header_tag::SORT_ORDER))
+        .is_some_and(|so| so.as_slice() == sort_order::COORDINATE)
+}
+
+/**
+    Classify a batch of query name groups, on the thread pool if any, then
+    update the statistics and write the outputs in the input order. The batch
+    is emptied.
+ */
+pub fn process_batch(
+    batch: &mut Vec<Vec<bam::Record>>,
+    pool: Option<&rayon::ThreadPool>,
+    headers: &sam::Header,
+    bed_ladder: &FragmentIndex,
+    options: &ClassifyOptions,
+    handlers: &mut OutputHandlers,
+    stats: &mut Statistics,
+    gtag: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let classified: Vec<ClassifiedGroup> = match pool {
+        Some(pool) => pool.install(|| {
+            batch.par_drain(..)
+                .map(|group| classify_read_group(group, headers, bed_ladder, options)
+                    .map_err(|e| e.to_string()))
+                .collect::<Result<Vec<_>, String>>()
+        })?,
+        None => batch.drain(..)
+            .map(|group| classify_read_group(group, headers, bed_ladder, options))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    
+    for group in classified {
+        match group {
+            ClassifiedGroup::Pair { pair, chimeric } => {
+                if chimeric {
+                    stats.chimeric_counter += 1;
+                }
+                process_read_pair(&pair, handlers, stats, gtag)?;
+            }
+            ClassifiedGroup::Orphan => stats.orphan_counter += 1,
+            ClassifiedGroup::Empty => {}
+        }
+    }
+    Ok(())
+}
+
+/// Records of a query name of a coordinate-sorted input, buffered until complete
+#[derive(Default)]
+struct PendingGroup {
+    records: Vec<bam::Record>,
+    /// Secondary records, not announced by the primary mates
+    secondary_records: Vec<bam::Record>,
+    primary_mates: usize,
+    /// Records announced by the primary mates, i.e. themselves and their split parts (SA tag)
+    expected_records: usize,
+}
+
+/// Whether the records of a query name miss one of the mates
+fn is_missing_mate(group: &[bam::Record]) -> bool {
+    let has_mate = |first: bool| group.iter()
+        .any(|record| !record.flags().is_secondary() && record.flags().is_first_segment() == first);
+    !has_mate(true) || !has_mate(false)
+}
+
+/**
+    Classify all the records of an input, grouped by query name. Records of a
+    coordinate-sorted input are buffered until both primary mates and the
+    supplementary records listed in their SA tags are found, so that chimeric
+    reads are rescued as for grouped inputs. Secondary records are only kept
+    when their group is still buffered. Groups still incomplete at the end
+    are classified as they are, e.g. as orphans. Otherwise,
+    the records of a query name have to be adjacent: a query name seen again
+    after its group missed a mate is an error, rather than silently counting
+    all the reads of an unsorted input as orphans. Only the latest
+    ORPHAN_NAME_WINDOW such names are remembered, to bound the memory usage on
+    inputs where most groups miss a mate (e.g. mapped reads only).
+
+    records : alignment records of the input
+    headers : header of the input
+    verbose : log the number of reads processed every 100000 reads
+ */
+pub fn process_records(
+    records: AlignmentRecords,
+    pool: Option<&rayon::ThreadPool>,
+    headers: &sam::Header,
+    bed_ladder: &FragmentIndex,
+    options: &ClassifyOptions,
+    handlers: &mut OutputHandlers,
+    stats: &mut Statistics,
+    gtag: Option<&str>,
+    verbose: bool,
+) -> Result<(), Box<dyn Error>> {
+    let coordinate_sorted = is_coordinate_sorted(headers);
+    if coordinate_sorted {
+        warn!("Warning: coordinate-sorted input, mates are buffered until paired. Use name-sorted/collated BAM to limit memory usage");
+    }
+    let mut batch: Vec<Vec<bam::Record>> = Vec::with_capacity(BATCH_SIZE);
+    let mut group: Vec<bam::Record> = Vec::new();
+    let mut pending_groups: HashMap<Vec<u8>, PendingGroup> = HashMap::new();
+    let mut orphan_names: HashSet<Vec<u8>> = HashSet::new();
+    let mut orphan_order: VecDeque<Vec<u8>> = VecDeque::new();
+
+    for result in records {
+        let record = result?;
+        stats.reads_counter += 1;
+
+        if coordinate_sorted {
+            let name = record.name().map(|n| n.to_vec()).unwrap_or_default();
+            if record.flags().is_secondary() {
+                if let Some(pending) = pending_groups.get_mut(&name) {
+                    pending.secondary_records.push(record);
+                }
+                continue;
+            }
+            let pending = pending_groups.entry(name.clone()).or_default();
+            if !record.flags().is_supplementary() {
+                pending.primary_mates += 1;
+                pending.expected_records += 1 + get_split_alignment_mapqs(&record).len();
+            }
+            pending.records.push(record);
+            let complete = pending.primary_mates == 2 && pending.records.len() >= pending.expected_records;
+            if complete && let Some(mut pending) = pending_groups.remove(&name) {
+                pending.records.append(&mut pending.secondary_records);
+                batch.push(pending.records);
+            }
+        } else {
+            if group.first().is_none_or(|first| first.name() != record.name()) {
+                if is_missing_mate(&group) && let Some(name) = group.first().and_then(|first| first.name()) {
+                    orphan_names.insert(name.to_vec());
+                    orphan_order.push_back(name.to_vec());
+                    if orphan_order.len() > ORPHAN_NAME_WINDOW && let Some(oldest) = orphan_order.pop_front() {
+                        orphan_names.remove(&oldest);
+                    }
+                }
+                if !group.is_empty() {
+                    batch.push(std::mem::take(&mut group));
+                }
+                if let Some(name) = record.name() && orphan_names.contains::<[u8]>(name.as_ref()) {
+                    return Err(format!("Input is not grouped by read name ({} seen again after its mate was missing). \
+                        Group it by name (samtools collate, samtools sort -n) or sort it by coordinate (samtools sort)",
+                        name).into());
+                }
+            }
+            group.push(record);
+        }
+
+        if batch.len() >= BATCH_SIZE {
+            process_batch(&mut batch, pool, headers, bed_ladder, options, handlers, stats, gtag)?;
+        }
+
+        if verbose && stats.reads_counter.is_multiple_of(100000) {
+            info!("## {}", stats.reads_counter);
+        }
+    }
+    if !group.is_empty() {
+        batch.push(group);
+    }
+    // Incomplete groups of a coordinate-sorted input, in a reproducible order
+    let mut pending_groups: Vec<(Vec<u8>, PendingGroup)> = pending_groups.into_iter().collect();
+    pending_groups.sort_unstable_by(|(name1, _), (name2, _)| name1.cmp(name2));
+    batch.extend(pending_groups.into_iter().map(|(_, mut pending)| {
+        pending.records.append(&mut pending.secondary_records);
+        pending.records
+    }));
+    process_batch(&mut batch, pool, headers, bed_ladder, options, handlers, stats, gtag)?;
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//! Per-mate alignment quality filters (MAPQ, flags, soft clipping, edit distance) and multi-mapper detection
+
+use crate::reads::{get_read_tag, get_soft_clip_fraction, get_split_alignment_mapqs};
+use noodles_bam as bam;
+use noodles_sam::alignment::record::data::field::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// Thresholds a mate has to pass for its pair to be classified, unset ones being ignored
+#[derive(Debug, Clone, Default)]
+pub struct QualityFilter {
+    pub min_mapq: Option<u8>,
+    /// SAM flags which must all be set
+    pub include_flags: u16,
+    /// SAM flags which must all be unset
+    pub exclude_flags: u16,
+    /// Maximum fraction of soft-clipped read bases
+    pub max_soft_clip: Option<f64>,
+    /// Maximum edit distance to the reference (NM tag)
+    pub max_edit_distance: Option<i64>,
+    /// What to do with pairs having an ambiguously aligned mate
+    pub multimap_policy: MultimapPolicy,
+}
+
+/// Handling of the pairs with a multi-mapped mate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MultimapPolicy {
+    /// Drop them as low quality pairs
+    Discard,
+    /// Classify them as the other pairs
+    #[default]
+    Keep,
+    /// Classify them, the multi-mapped mates being typed "M" in the .pairs output (pairtools pair_type)
+    Label,
+}
+
+impl FromStr for MultimapPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "discard" => Ok(MultimapPolicy::Discard),
+            "keep" => Ok(MultimapPolicy::Keep),
+            "label" => Ok(MultimapPolicy::Label),
+            _ => Err(format!("invalid multi-mapper policy '{}', expected discard, keep or label", value)),
+        }
+    }
+}
+
+impl fmt::Display for MultimapPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = match self {
+            MultimapPolicy::Discard => "discard",
+            MultimapPolicy::Keep => "keep",
+            MultimapPolicy::Label => "label",
+        };
+        f.write_str(policy)
+    }
+}
+
+/// Why an alignment is considered ambiguous, in order of precedence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MultimapEvidence {
+    /// Mapping quality of 0
+    MapqZero,
+    /// Best and second best alignment scores are equal (AS == XS)
+    EqualScore,
+    /// Alternative hits listed by the aligner (XA tag)
+    AlternativeHits,
+    /// Another part of a split alignment (SA tag) with a MAPQ of 0
+    AmbiguousSplit,
+}
+
+impl MultimapEvidence {
+    pub const ALL: [MultimapEvidence; 4] = [
+        MultimapEvidence::MapqZero,
+        MultimapEvidence::EqualScore,
+        MultimapEvidence::AlternativeHits,
+        MultimapEvidence::AmbiguousSplit,
+    ];
+}
+
+impl fmt::Display for MultimapEvidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let evidence = match self {
+            MultimapEvidence::MapqZero => "mapq_zero",
+            MultimapEvidence::EqualScore => "equal_score",
+            MultimapEvidence::AlternativeHits => "alternative_hits",
+            MultimapEvidence::AmbiguousSplit => "ambiguous_split",
+        };
+        f.write_str(evidence)
+    }
+}
+
+/// Why a pair was dropped by the quality filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum QualityReason {
+    LowMapq,
+    /// Some of the required flags are unset
+    MissingFlags,
+    /// Some of the excluded flags are set
+    ExcludedFlags,
+    SoftClipped,
+    EditDistance,
+    /// A mate is multi-mapped, with the discard policy
+    Multimapped,
+}
+
+impl QualityReason {
+    pub const ALL: [QualityReason; 6] = [
+        QualityReason::LowMapq,
+        QualityReason::MissingFlags,
+        QualityReason::ExcludedFlags,
+        QualityReason::SoftClipped,
+        QualityReason::EditDistance,
+        QualityReason::Multimapped,
+    ];
+}
+
+/// Parse a SAM flag mask, in decimal or hexadecimal with a 0x prefix
+pub fn parse_flag_mask(value: &str) -> Result<u16, String> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|e| format!("invalid SAM flag mask '{}': {}", value, e))
+}
+
+impl fmt::Display for QualityReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            QualityReason::LowMapq => "low_mapq",
+            QualityReason::MissingFlags => "missing_flags",
+            QualityReason::ExcludedFlags => "excluded_flags",
+            QualityReason::SoftClipped => "soft_clipped",
+            QualityReason::EditDistance => "edit_distance",
+            QualityReason::Multimapped => "multimapped",
+        };
+        f.write_str(reason)
+    }
+}
+
+/**
+    First quality filter failed by a mate, None if it passes all of them. The
+    flag masks apply to all mates, the alignment filters to mapped mates only.
+    Mates without NM tag pass the edit distance filter.
+
+    read : [bam::Record]
+    filter : quality thresholds
+ */
+pub fn check_alignment_quality(read: &bam::Record, filter: &QualityFilter) -> Option<QualityReason> {
+    let flags = u16::from(read.flags());
+    if flags & filter.include_flags != filter.include_flags {
+        return Some(QualityReason::MissingFlags);
+    }
+    if flags & filter.exclude_flags != 0 {
+        return Some(QualityReason::ExcludedFlags);
+    }
+    if read.flags().is_unmapped() {
+        return None;
+    }
+    if let Some(min_mapq) = filter.min_mapq {
+        // A missing MAPQ (255) is unknown quality
+        if read.mapping_quality().is_none_or(|mapq| mapq.get() < min_mapq) {
+            return Some(QualityReason::LowMapq);
+        }
+    }
+    if let Some(max_soft_clip) = filter.max_soft_clip
+        && get_soft_clip_fraction(read).is_some_and(|fraction| fraction > max_soft_clip) {
+        return Some(QualityReason::SoftClipped);
+    }
+    if let Some(max_edit_distance) = filter.max_edit_distance
+        && get_read_tag(read, "NM").is_some_and(|nm| nm > max_edit_distance) {
+        return Some(QualityReason::EditDistance);
+    }
+    None
+}
+
+/**
+    Evidence that a mapped mate is ambiguously aligned, as aligners report it:
+    a MAPQ of 0, equal best and second best scores (bowtie2/BWA AS and XS
+    tags), alternative hits (BWA XA:Z tag, unlike the integer HiC-Pro allele
+    XA:i tag) or a part of a split alignment
+    mapping with a MAPQ of 0 (SA tag). Split alignments themselves are
+    ligation junctions, rescued as chimeric reads, not multi-mappers. None for
+    uniquely mapped and unmapped mates.
+
+    read : [bam::Record]
+ */
+pub fn get_multimap_evidence(read: &bam::Record) -> Option<MultimapEvidence> {
+    if read.flags().is_unmapped() {
+        return None;
+    }
+    if read.mapping_quality().is_some_and(|mapq| mapq.get() == 0) {
+        return Some(MultimapEvidence::MapqZero);
+    }
+    if let (Some(score), Some(suboptimal_score)) = (get_read_tag(read, "AS"), get_read_tag(read, "XS"))
+        && score == suboptimal_score {
+        return Some(MultimapEvidence::EqualScore);
+    }
+    if let Some(Ok(Value::String(_))) = read.data().get(b"XA") {
+        return Some(MultimapEvidence::AlternativeHits);
+    }
+    if get_split_alignment_mapqs(read).contains(&0) {
+        return Some(MultimapEvidence::AmbiguousSplit);
+    }
+    None
+}
+
+/// pairtools code of a mate in the pair_type column: M if multi-mapped, else U
+pub fn get_mate_type(multimap: Option<MultimapEvidence>) -> char {
+    if multimap.is_some() { 'M' } else { 'U' }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::tests::encode_records;
+
+    #[test]
+    fn test_get_multimap_evidence() {
+        let records = encode_records(&[
+            "unique\t0\tchr1\t100\t60\t10M\t*\t0\t0\tACGTACGTAC\t*\tAS:i:10\tXS:i:5",
+            "mapq0\t0\tchr1\t100\t0\t10M\t*\t0\t0\tACGTACGTAC\t*",
+            "equal\t0\tchr1\t100\t1\t10M\t*\t0\t0\tACGTACGTAC\t*\tAS:i:10\tXS:i:10",
+            "bwa\t0\tchr1\t100\t30\t10M\t*\t0\t0\tACGTACGTAC\t*\tXA:Z:chr2,+500,10M,0;",
+            "allele\t0\tchr1\t100\t60\t10M\t*\t0\t0\tACGTACGTAC\t*\tXA:i:1",
+            "split\t0\tchr1\t100\t60\t5M5S\t*\t0\t0\tACGTACGTAC\t*\tSA:Z:chr2,500,+,5S5M,60,0;",
+            "split0\t0\tchr1\t100\t60\t5M5S\t*\t0\t0\tACGTACGTAC\t*\tSA:Z:chr2,500,+,5S5M,0,0;",
+            "unmapped\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTAC\t*",
+        ]);
+        let evidence: Vec<_> = records.iter().map(get_multimap_evidence).collect();
+        assert_eq!(evidence, [
+            None,
+            Some(MultimapEvidence::MapqZero),
+            Some(MultimapEvidence::EqualScore),
+            Some(MultimapEvidence::AlternativeHits),
+            None,
+            None,
+            Some(MultimapEvidence::AmbiguousSplit),
+            None,
+        ]);
+        assert_eq!(evidence.iter().map(|e| get_mate_type(*e)).collect::<String>(), "UMMMUUMU");
+    }
+}
@@ -0,0 +1,182 @@
+//! Positions, strands and tags of BAM records
+
+use noodles_bam as bam;
+use noodles_sam::alignment::Record;
+use noodles_sam::alignment::record::cigar::{op::Kind, Op};
+use noodles_sam::alignment::record::data::field::Value;
+
+/// 1-based position of a read: "start" (5' end), "left" (leftmost base) or "middle" (0-based center), None if unaligned
+pub(crate) fn get_read_pos(read:&bam::Record, st: &str) -> Option<usize>{
+    match st {
+        "middle" => {
+            let start =  read.alignment_start().transpose().ok().flatten()?.get();
+            let start0based = start -1;
+            let span = read.alignment_span().transpose().ok().flatten()?;
+            let pos = start0based + span / 2;
+            Some(pos)
+        }
+        "start" => get_read_start(read),
+        "left" => Some(read.alignment_start().transpose().ok().flatten()?.get()),
+        _ => None,
+    }
+}
+
+/// 1-based 5' start of a read, None if unaligned
+pub fn get_read_start(read: &bam::Record) -> Option<usize>{
+    let start = read.alignment_start().transpose().ok().flatten()?.get();
+    let pos = if read.flags().is_reverse_complemented(){
+        let span = read.alignment_span().transpose().ok().flatten()?;
+        start + span - 1
+    } else{
+        start
+    };
+    Some(pos)
+}
+
+/// Number of read bases clipped before the 5' end of the alignment
+pub fn get_read_5prime_clip(read: &bam::Record) -> usize {
+    let ops: Vec<Op> = read.cigar().iter().filter_map(Result::ok).collect();
+    let is_clip = |op: &&Op| matches!(op.kind(), Kind::SoftClip | Kind::HardClip);
+    if read.flags().is_reverse_complemented() {
+        ops.iter().rev().take_while(is_clip).map(|op| op.len()).sum()
+    } else {
+        ops.iter().take_while(is_clip).map(|op| op.len()).sum()
+    }
+}
+
+/// Fraction of the read bases soft clipped in the alignment, None without read bases
+pub fn get_soft_clip_fraction(read: &bam::Record) -> Option<f64> {
+    let ops: Vec<Op> = read.cigar().iter().filter_map(Result::ok).collect();
+    let read_len: usize = ops.iter().filter(|op| op.kind().consumes_read()).map(|op| op.len()).sum();
+    let clipped: usize = ops.iter().filter(|op| op.kind() == Kind::SoftClip).map(|op| op.len()).sum();
+    (read_len > 0).then(|| clipped as f64 / read_len as f64)
+}
+
+/**
+    Select the alignment of a mate covering the 5' end of the read among its
+    primary and supplementary (split) alignments, as pairtools does for chimeric
+    bwa-mem reads. Unmapped records are only kept if nothing else is available.
+    The selected record is removed from the alignments, leaving the other ones.
+
+    alignments : primary and supplementary records of the same mate
+ */
+pub fn select_5prime_alignment(alignments: &mut Vec<bam::Record>) -> Option<bam::Record> {
+    let index = alignments.iter().enumerate()
+        .filter(|(_, read)| !read.flags().is_unmapped())
+        .min_by_key(|(_, read)| (get_read_5prime_clip(read), read.flags().is_supplementary()))
+        .map(|(index, _)| index)
+        .or((!alignments.is_empty()).then_some(0))?;
+    Some(alignments.remove(index))
+}
+
+/// Strand of a read, "+" or "-"
+pub fn get_read_strand(read: &bam::Record) -> &'static str{
+    if read.flags().is_reverse_complemented(){
+        "-"
+    } else {
+        "+"
+    }
+}
+
+/**
+    Return the integer value of an optional tag of a read, as HiC-Pro does for
+    the allele-specific status (e.g. XA:i:0/1/2/3 from SNPsplit-style tagging)
+
+    read : [bam::Record]
+    tag : two letters tag name
+ */
+pub fn get_read_tag(read: &bam::Record, tag: &str) -> Option<i64> {
+    let tag: [u8; 2] = tag.as_bytes().try_into().ok()?;
+    match read.data().get(&tag)?.ok()? {
+        Value::String(s) | Value::Hex(s) => std::str::from_utf8(s).ok()?.trim().parse().ok(),
+        Value::Character(c) => (c as char).to_digit(10).map(i64::from),
+        value => value.as_int(),
+    }
+}
+
+/**
+    Mapping qualities of the other parts of a split alignment, from the SA tag
+    (rname,pos,strand,CIGAR,mapQ,NM; per part). Empty without SA tag.
+
+    read : [bam::Record]
+ */
+pub fn get_split_alignment_mapqs(read: &bam::Record) -> Vec<u8> {
+    match read.data().get(b"SA").and_then(Result::ok) {
+        Some(Value::String(parts)) => parts.split(|&c| c == b';')
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                std::str::from_utf8(part).ok()
+                    .and_then(|part| part.split(',').nth(4))
+                    .and_then(|mapq| mapq.parse().ok())
+                    .unwrap_or(0)
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Allele-specific code "x-y" of an ordered pair, missing tags being reported as 0
+pub fn get_allele_tag(read1: &bam::Record, read2: &bam::Record, gtag: &str) -> String {
+    let r1as = get_read_tag(read1, gtag).unwrap_or(0);
+    let r2as = get_read_tag(read2, gtag).unwrap_or(0);
+    format!("{}-{}", r1as, r2as)
+}
+
+
+/// Whether both reads map to the same chromosome, None if one of them is unmapped
+pub fn is_intra_chrom(read1:  &bam::Record, read2 :  &bam::Record) -> Option<bool>{
+    let tid1_opt = read1.reference_sequence_id().transpose().ok().flatten();
+    let tid2_opt = read2.reference_sequence_id().transpose().ok().flatten();
+    
+    if let (Some(tid1), Some(tid2)) = (tid1_opt, tid2_opt) {
+        Some(tid1 == tid2)
+    } else {
+        None
+    }
+}
+
+/**
+    Calculate the contact distance between two intrachromosomal reads
+
+    read1 : [bam::Record]
+    read2 : [bam::Record]
+ */
+pub fn get_cis_distance(read1:  &bam::Record, read2 :  &bam::Record) -> Option<usize>{
+    let mut dist = None;
+    let unmap1 = read1.flags().is_unmapped();
+    let unmap2 = read2.flags().is_unmapped();
+    if !unmap1 && !unmap2{
+        let (r1pos, r2pos) = get_read_start(read1).zip(get_read_start(read2))?;
+        if r1pos > r2pos {
+            dist = Some(r1pos - r2pos); //maintain same as hic-pro statistics
+        } else {
+            dist = Some(r2pos - r1pos);
+        }
+    }
+    dist
+}
+
+/// Reads ordered by chromosome index then 5' position, as in the validPairs output
+pub fn get_ordered_reads<'a>(read1: &'a bam::Record, read2: &'a bam::Record) 
+    -> Option<(&'a bam::Record, &'a bam::Record)>{
+    let tid1_opt = read1.reference_sequence_id().transpose().ok().flatten();
+    let tid2_opt = read2.reference_sequence_id().transpose().ok().flatten();
+    tid1_opt.zip(tid2_opt).and_then(|(tid1, tid2)| {
+        if tid1 < tid2 {
+            Some((read1,read2))
+        } else if tid1 > tid2{
+            Some((read2, read1))
+        } else {
+            let r1pos_opt = get_read_pos(read1, "start");
+            let r2pos_opt = get_read_pos(read2, "start");
+            r1pos_opt.zip(r2pos_opt).map(|(r1pos, r2pos)| {
+                // We have valid positions. Sort by position.
+                if r1pos <= r2pos {
+                    (read1, read2)
+                } else {
+                    (read2, read1)
+                }
+            })
+        }
+    })
+}
@@ -0,0 +1,187 @@
+//! JSON and MultiQC custom-content reports of the classification statistics
+
+use crate::classify::{DumpReason, FilterReason};
+use crate::quality::{MultimapEvidence, QualityReason};
+use crate::stats::{get_duplicate_summary, Statistics};
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Description of a run, reported alongside the statistics
+#[derive(Debug, Default)]
+pub struct RunInfo {
+    /// Command line parameters, as name/value pairs
+    pub parameters: Value,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    /// Statistics of each input, when there are several
+    pub input_statistics: Vec<(String, Value)>,
+    pub runtime: Duration,
+}
+
+/**
+    All the counters of the .RSstat report as a JSON object. The duplicate and
+    allele specific sections are null when not computed.
+
+    stats : classification statistics
+    rmdup : duplicates were detected
+    allele_specific : a genotype tag was given
+ */
+pub fn get_statistics_json(stats: &Statistics, rmdup: bool, allele_specific: bool) -> Value {
+    let filt_reasons: Map<String, Value> = FilterReason::ALL.iter()
+        .map(|reason| (reason.to_string(), json!(stats.filt_reasons.get(reason).unwrap_or(&0))))
+        .collect();
+    let dump_reasons: Map<String, Value> = DumpReason::ALL.iter()
+        .map(|reason| (reason.to_string(), json!(stats.dump_reasons.get(reason).unwrap_or(&0))))
+        .collect();
+    let lowq_reasons: Map<String, Value> = QualityReason::ALL.iter()
+        .map(|reason| (reason.to_string(), json!(stats.lowq_reasons.get(reason).unwrap_or(&0))))
+        .collect();
+    let multimap_evidence: Map<String, Value> = MultimapEvidence::ALL.iter()
+        .map(|evidence| (evidence.to_string(), json!(stats.multimap_evidence.get(evidence).unwrap_or(&0))))
+        .collect();
+
+    let duplicates = if rmdup {
+        let (unique_pairs, duplication_rate, library_size) = get_duplicate_summary(stats);
+        json!({
+            "duplicate_pairs": stats.duplicate_counter,
+            "valid_pairs_rmdup": unique_pairs,
+            "duplication_rate": duplication_rate,
+            "library_complexity": library_size,
+        })
+    } else {
+        Value::Null
+    };
+
+    let allele = if allele_specific {
+        json!({
+            "ref_ref": stats.g1g1_ascounter,
+            "ref_unassigned": stats.ug1_ascounter + stats.g1u_ascounter,
+            "alt_alt": stats.g2g2_ascounter,
+            "alt_unassigned": stats.ug2_ascounter + stats.g2u_ascounter,
+            "ref_alt": stats.g1g2_ascounter + stats.g2g1_ascounter,
+            "unassigned_unassigned": stats.uu_ascounter,
+            "conflicting": stats.cf_ascounter,
+        })
+    } else {
+        Value::Null
+    };
+
+    json!({
+        "reads": stats.reads_counter,
+        "valid_pairs": stats.valid_counter,
+        "valid_pairs_ff": stats.valid_counter_ff,
+        "valid_pairs_rr": stats.valid_counter_rr,
+        "valid_pairs_rf": stats.valid_counter_rf,
+        "valid_pairs_fr": stats.valid_counter_fr,
+        "dangling_end_pairs": stats.de_counter,
+        "religation_pairs": stats.re_counter,
+        "self_circle_pairs": stats.sc_counter,
+        "single_end_pairs": stats.single_counter,
+        "filtered_pairs": stats.filt_counter,
+        "dumped_pairs": stats.dump_counter,
+        "low_quality_pairs": stats.lowq_counter,
+        "orphan_mates": stats.orphan_counter,
+        "chimeric_pairs": stats.chimeric_counter,
+        "cis_pairs": stats.cis_counter,
+        "cis_short_range_pairs": stats.cis_short_counter,
+        "cis_long_range_pairs": stats.cis_long_counter,
+        "trans_pairs": stats.trans_counter,
+        "filtered_pairs_per_reason": filt_reasons,
+        "dumped_pairs_per_reason": dump_reasons,
+        "low_quality_pairs_per_reason": lowq_reasons,
+        "multimapped_pairs": stats.multimap_counter,
+        "multimapped_pairs_per_evidence": multimap_evidence,
+        "ligation_junctions": stats.ligation_junctions,
+        "duplicates": duplicates,
+        "allele_specific": allele,
+    })
+}
+
+/// Write the <base>.stats.json report: tool version, run description and statistics
+pub fn write_json_report(stats: &Statistics, run: &RunInfo, output_dir: &Path, base_name: &str,
+    rmdup: bool, allele_specific: bool) -> Result<(), Box<dyn Error>> {
+    let report = json!({
+        "tool": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "sample": base_name,
+        "runtime_seconds": run.runtime.as_secs_f64(),
+        "parameters": run.parameters,
+        "inputs": run.inputs,
+        "outputs": run.outputs,
+        "statistics": get_statistics_json(stats, rmdup, allele_specific),
+    });
+    let mut report = report;
+    if !run.input_statistics.is_empty() {
+        report["input_statistics"] = run.input_statistics.iter()
+            .map(|(input, statistics)| json!({ "input": input, "statistics": statistics }))
+            .collect();
+    }
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("{}.stats.json", base_name)))?);
+    serde_json::to_writer_pretty(&mut writer, &report)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/**
+    Write the <base>_mqc.json MultiQC custom-content file, a bar graph of the
+    pair classes of the sample. MultiQC picks it up from the output directory
+    and merges the bar graphs of all samples.
+ */
+pub fn write_multiqc_report(stats: &Statistics, output_dir: &Path, base_name: &str,
+    rmdup: bool) -> Result<(), Box<dyn Error>> {
+    let mut classes = Map::new();
+    if rmdup {
+        classes.insert("Valid pairs (unique)".to_string(), json!(stats.valid_counter - stats.duplicate_counter));
+        classes.insert("Duplicate pairs".to_string(), json!(stats.duplicate_counter));
+    } else {
+        classes.insert("Valid pairs".to_string(), json!(stats.valid_counter));
+    }
+    classes.insert("Dangling end pairs".to_string(), json!(stats.de_counter));
+    classes.insert("Religation pairs".to_string(), json!(stats.re_counter));
+    classes.insert("Self circle pairs".to_string(), json!(stats.sc_counter));
+    classes.insert("Single-end pairs".to_string(), json!(stats.single_counter));
+    classes.insert("Filtered pairs".to_string(), json!(stats.filt_counter));
+    classes.insert("Dumped pairs".to_string(), json!(stats.dump_counter));
+    classes.insert("Low quality pairs".to_string(), json!(stats.lowq_counter));
+
+    let report = json!({
+        "id": "hic2frag_pair_classes",
+        "section_name": "hic2frag read pairs",
+        "description": "Classification of the read pairs by hic2frag",
+        "plot_type": "bargraph",
+        "pconfig": {
+            "id": "hic2frag_pair_classes_plot",
+            "title": "hic2frag: read pair classes",
+            "ylab": "Read pairs",
+        },
+        "data": { base_name: classes },
+    });
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("{}_mqc.json", base_name)))?);
+    serde_json::to_writer_pretty(&mut writer, &report)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Files of the sample written in the output directory since the start of the run
+pub fn list_output_files(output_dir: &Path, base_name: &str, since: SystemTime) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(output_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_sample_file = name.to_str().is_some_and(|name| {
+            name.strip_prefix(base_name).is_some_and(|rest| rest.starts_with(['.', '_']))
+        });
+        let metadata = entry.metadata()?;
+        if is_sample_file && metadata.is_file() && metadata.modified()? >= since {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
@@ -0,0 +1,392 @@
+//! End-to-end classification run, from the alignment inputs to the outputs and reports
+
+use crate::classify::ClassifyOptions;
+use crate::dedup;
+use crate::fragments::{convert_vec_to_lapper, filter_fragments_by_size, get_fragment_sites, load_restriction_fragments};
+use crate::hic;
+use crate::histogram::{write_cis_distance_report, write_insert_size_report};
+use crate::input::{open_alignment_reader, open_mate_reader, STDIN};
+use crate::output::{create_output_handlers, get_chrom_sizes, write_cool_output, DuplicateOutput};
+use crate::pipeline::{process_records, split_threads};
+use crate::quality::MultimapPolicy;
+use crate::report::{get_statistics_json, list_output_files, write_json_report, write_multiqc_report, RunInfo};
+use crate::stats::{merge_statistics, write_chrom_pair_table, write_statistics, Statistics};
+use log::info;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+/// Settings of a run, i.e. everything the command line sets
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    /// Alignment files ('-' for stdin), processed into the same outputs
+    pub inputs: Vec<String>,
+    /// R1 and R2 files aligned as single-end reads, instead of the inputs
+    pub mates: Option<(String, String)>,
+    /// Indexed reference FASTA to decode CRAM input
+    pub reference: Option<PathBuf>,
+    /// Restriction fragment files, none for the enzyme-free mode
+    pub fragment_files: Vec<String>,
+    pub min_frag_size: Option<u64>,
+    pub max_frag_size: Option<u64>,
+    /// Pair classification and filters, the fragment mode being set from the fragment files
+    pub classify: ClassifyOptions,
+    /// Genotype tag for allele specific classification
+    pub gtag: Option<String>,
+    pub output_dir: PathBuf,
+    /// Prefix of the output files, by default the name of the first input
+    pub prefix: Option<String>,
+    /// Write the valid pairs to stdout
+    pub to_stdout: bool,
+    /// Write all the pair classes, not only the valid pairs
+    pub all_output: bool,
+    pub sam_output: bool,
+    /// Write a 4DN .pairs file, bgzip compressed or not
+    pub pairs_output: Option<bool>,
+    pub matrix_resolutions: Vec<u64>,
+    /// Bin size of the .cool output, zoomed into an .mcool with mcool
+    pub cool: Option<u64>,
+    pub mcool: bool,
+    pub rmdup: bool,
+    pub dup_tolerance: u64,
+    pub hic: bool,
+    pub hic_resolutions: Vec<u64>,
+    pub hic_frag_resolutions: Vec<u64>,
+    pub histograms: bool,
+    pub json: bool,
+    pub threads: usize,
+    pub verbose: bool,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        RunConfig {
+            inputs: Vec::new(),
+            mates: None,
+            reference: None,
+            fragment_files: Vec::new(),
+            min_frag_size: None,
+            max_frag_size: None,
+            classify: ClassifyOptions::default(),
+            gtag: None,
+            output_dir: PathBuf::from("."),
+            prefix: None,
+            to_stdout: false,
+            all_output: false,
+            sam_output: false,
+            pairs_output: None,
+            matrix_resolutions: Vec::new(),
+            cool: None,
+            mcool: false,
+            rmdup: false,
+            dup_tolerance: 0,
+            hic: false,
+            hic_resolutions: hic::DEFAULT_BP_RESOLUTIONS.to_vec(),
+            hic_frag_resolutions: Vec::new(),
+            histograms: false,
+            json: false,
+            threads: 1,
+            verbose: false,
+        }
+    }
+}
+
+/// Check the settings which cannot be run, before any output is written
+fn check_config(config: &RunConfig, inputs: &[String]) -> Result<(), Box<dyn Error>> {
+    let enzyme_free = config.fragment_files.is_empty();
+    if config.threads == 0 {
+        return Err("Number of threads must be positive".into());
+    }
+    if config.matrix_resolutions.contains(&0) || config.cool == Some(0)
+        || config.hic_resolutions.contains(&0) || config.hic_frag_resolutions.contains(&0) {
+        return Err("Matrix resolution must be a positive bin size".into());
+    }
+    if config.cool.is_some() && !cfg!(feature = "cool") {
+        return Err("hic2frag was built without .cool support, rebuild with --features cool".into());
+    }
+    if !config.hic_frag_resolutions.is_empty() && enzyme_free {
+        return Err("Fragment resolutions of the .hic file require a restriction fragment file".into());
+    }
+    if (config.classify.min_insert_size.is_some() || config.classify.max_insert_size.is_some()) && enzyme_free {
+        // Insert sizes are measured to the restriction sites, use the per-orientation distances instead
+        return Err("Insert size filters require a restriction fragment file, see --min-dist-fr/rf/ff/rr in enzyme-free mode".into());
+    }
+    if inputs.is_empty() {
+        return Err("Missing --bam argument".into());
+    }
+    if inputs.len() > 1 && config.prefix.is_none() {
+        return Err("Several input files require an output --prefix".into());
+    }
+    let r2_input = config.mates.as_ref().map(|(_, r2)| r2);
+    if inputs.iter().chain(r2_input).filter(|input| *input == STDIN).count() > 1 {
+        return Err("Stdin can only be read once".into());
+    }
+    if let Some(gtag) = config.gtag.as_deref().filter(|tag| tag.len() != 2) {
+        return Err(format!("Genotype tag must be a two letters SAM tag, got '{}'", gtag).into());
+    }
+    Ok(())
+}
+
+/// Parameters of the JSON report
+fn get_report_parameters(config: &RunConfig) -> Value {
+    let options = &config.classify;
+    json!({
+        "enzyme_free": config.fragment_files.is_empty(),
+        "min_insert_size": options.min_insert_size,
+        "max_insert_size": options.max_insert_size,
+        "min_frag_size": config.min_frag_size,
+        "max_frag_size": config.max_frag_size,
+        "min_cis_dist": options.min_cis_dist,
+        "min_dist_fr": options.min_dist_fr,
+        "min_dist_rf": options.min_dist_rf,
+        "min_dist_ff": options.min_dist_ff,
+        "min_dist_rr": options.min_dist_rr,
+        "min_mapq": options.quality.min_mapq,
+        "include_flags": options.quality.include_flags,
+        "exclude_flags": options.quality.exclude_flags,
+        "max_soft_clip": options.quality.max_soft_clip,
+        "max_edit_distance": options.quality.max_edit_distance,
+        "multimap": options.quality.multimap_policy.to_string(),
+        "gtag": config.gtag,
+        "all_output": config.all_output,
+        "sam_output": config.sam_output,
+        "format": if config.pairs_output.is_some() { "Pairs" } else { "ValidPairs" },
+        "bgzip": config.pairs_output.unwrap_or(false),
+        "matrix_resolutions": config.matrix_resolutions,
+        "cool": config.cool,
+        "mcool": config.mcool,
+        "rmdup": config.rmdup,
+        "dup_tolerance": config.dup_tolerance,
+        "hic": config.hic,
+        "hic_resolutions": config.hic_resolutions,
+        "hic_frag_resolutions": config.hic_frag_resolutions,
+        "histograms": config.histograms,
+        "stdout": config.to_stdout,
+        "threads": config.threads,
+    })
+}
+
+/// Bin sizes of all the contact matrices to build (HiC-Pro and cooler outputs)
+fn get_matrix_resolutions(config: &RunConfig) -> Vec<u64> {
+    let mut resolutions = config.matrix_resolutions.clone();
+    resolutions.extend(config.cool);
+    resolutions.sort_unstable();
+    resolutions.dedup();
+    resolutions
+}
+
+/**
+    Classify the read pairs of the inputs into the outputs of the run: pair
+    files, BAM, matrices, .hic, statistics and reports. Return the statistics,
+    summed over the inputs.
+
+    config : settings of the run
+ */
+pub fn run(config: &RunConfig) -> Result<Statistics, Box<dyn Error>> {
+    let (start, start_time) = (Instant::now(), SystemTime::now());
+    let inputs = match config.mates {
+        // R1 and R2 are processed as a single input
+        Some((ref r1_input, _)) => vec![r1_input.clone()],
+        None => config.inputs.clone(),
+    };
+    check_config(config, &inputs)?;
+    let bam_file = inputs[0].clone();
+    let fragment_file = config.fragment_files.join(",");
+    let enzyme_free = config.fragment_files.is_empty();
+    let gtag = config.gtag.as_ref();
+
+    // Set up output directory
+    let output_dir = &config.output_dir;
+    std::fs::create_dir_all(output_dir)?;
+
+    // Get base name for output files
+    let bam_path = PathBuf::from(&bam_file);
+    let base_name = match config.prefix.as_deref() {
+        Some(prefix) => prefix,
+        None if bam_file == STDIN => "output",
+        None => bam_path.file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("output"),
+    };
+
+    if config.verbose {
+        info!("## HiC-Pro Rust Implementation");
+        info!("## mappedReadsFile= {}", inputs.join(","));
+        info!("## fragmentFile= {}", if enzyme_free { "None (enzyme-free mode)" } else { &fragment_file });
+        info!("## minInsertSize= {:?}", config.classify.min_insert_size);
+        info!("## maxInsertSize= {:?}", config.classify.max_insert_size);
+        info!("## minFragSize= {:?}", config.min_frag_size);
+        info!("## maxFragSize= {:?}", config.max_frag_size);
+        info!("## genotypeTag= {:?}", config.gtag);
+        info!("## allOutput= {}", config.all_output);
+        info!("## SAM output= {}", config.sam_output);
+        info!("## verbose= {}", config.verbose);
+    }
+
+    // Load restriction fragments
+    if config.verbose && !enzyme_free {
+        info!("## Loading Restriction File Intervals {} ...", fragment_file);
+    }
+    let bed_rec = load_restriction_fragments(&config.fragment_files)?;
+    // The .hic fragment sites are those of the full restriction map
+    let site_fragments = if config.hic { bed_rec.clone() } else { Vec::new() };
+
+    // Filter fragments by size if specified, for the read lookup only
+    let filtered_bed_rec = filter_fragments_by_size(bed_rec, config.min_frag_size, config.max_frag_size);
+
+    let bed_ladder = convert_vec_to_lapper(&filtered_bed_rec);
+
+    // Open BAM file
+    let (decompression_threads, classification_threads) = split_threads(config.threads);
+    if config.verbose {
+        info!("## Opening alignment file {} ...", bam_file);
+    }
+    let reference = config.reference.as_deref();
+    let (headers, first_records) = match config.mates {
+        Some((ref r1_input, ref r2_input)) => open_mate_reader(r1_input, r2_input, reference, decompression_threads)?,
+        None => open_alignment_reader(&bam_file, reference, decompression_threads)?,
+    };
+
+    // Create output handlers
+    let mut handlers = create_output_handlers(output_dir, base_name, config.all_output, config.sam_output,
+        config.pairs_output, config.to_stdout, &get_matrix_resolutions(config), &headers)?;
+    if config.rmdup {
+        let dup_file = output_dir.join(format!("{}.dupPairs", base_name));
+        handlers.duplicates = Some(DuplicateOutput {
+            filter: dedup::DuplicateFilter::new(config.dup_tolerance),
+            writer: BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(dup_file)?),
+        });
+    }
+    if config.hic {
+        let chrom_sizes = get_chrom_sizes(&headers);
+        let sites = get_fragment_sites(&chrom_sizes, &site_fragments);
+        handlers.hic = Some(hic::HicFile::new(&chrom_sizes, &config.hic_resolutions, &config.hic_frag_resolutions, sites));
+    }
+    handlers.label_multimapped = config.classify.quality.multimap_policy == MultimapPolicy::Label;
+
+    if config.verbose {
+        info!("## Classifying Interactions ...");
+    }
+
+    // Process the inputs one after the other into the same outputs
+    let options = ClassifyOptions { enzyme_free, ..config.classify.clone() };
+    let pool = if classification_threads > 1 {
+        Some(rayon::ThreadPoolBuilder::new().num_threads(classification_threads).build()?)
+    } else {
+        None
+    };
+    let mut input_stats: Vec<(String, Statistics)> = Vec::new();
+    let mut first_records = Some(first_records);
+    for input in &inputs {
+        let records = match first_records.take() {
+            Some(records) => records,
+            None => {
+                if config.verbose {
+                    info!("## Opening alignment file {} ...", input);
+                }
+                let (input_headers, records) = open_alignment_reader(input, reference, decompression_threads)?;
+                if input_headers.reference_sequences() != headers.reference_sequences() {
+                    return Err(format!("Reference sequences of {} differ from those of {}", input, inputs[0]).into());
+                }
+                records
+            }
+        };
+        let mut stats = Statistics::default();
+        process_records(records, pool.as_ref(), &headers, &bed_ladder, &options, &mut handlers, &mut stats,
+            config.gtag.as_deref(), config.verbose)?;
+        input_stats.push((input.clone(), stats));
+    }
+    let mut stats = Statistics::default();
+    for (_, input) in &input_stats {
+        merge_statistics(&mut stats, input);
+    }
+
+    if let Some(ref mut bam_writer) = handlers.sam {
+        bam_writer.writer.try_finish()?;
+    }
+    handlers.valid.flush()?;
+    if let Some(ref mut pairs) = handlers.pairs {
+        pairs.flush()?;
+    }
+    if let Some(ref mut duplicates) = handlers.duplicates {
+        duplicates.writer.flush()?;
+    }
+    for contact_matrix in &handlers.matrices {
+        if config.matrix_resolutions.contains(&contact_matrix.resolution) {
+            if config.verbose {
+                info!("## Writing contact matrix at {} bp resolution ({} bins) ...",
+                    contact_matrix.resolution, contact_matrix.n_bins());
+            }
+            contact_matrix.write_hicpro(output_dir, base_name)?;
+        }
+        if config.cool == Some(contact_matrix.resolution) {
+            let zoom_levels = if config.mcool { contact_matrix.zoom_pyramid() } else { Vec::new() };
+            write_cool_output(contact_matrix, &zoom_levels, output_dir, base_name, config.mcool)?;
+        }
+    }
+    if let Some(ref hic_file) = handlers.hic {
+        if config.verbose {
+            info!("## Writing .hic file ...");
+        }
+        hic_file.write(&output_dir.join(format!("{}.hic", base_name)))?;
+    }
+
+    // Write statistics
+    write_statistics(&stats, output_dir, base_name, gtag, config.rmdup)?;
+    if input_stats.len() > 1 {
+        // Per-input statistics, named after the inputs
+        let mut input_names: Vec<String> = Vec::new();
+        for (index, (input, stats)) in input_stats.iter().enumerate() {
+            let mut name = Path::new(input).file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("input")
+                .to_string();
+            if input_names.contains(&name) {
+                name = format!("{}_{}", name, index + 1);
+            }
+            write_statistics(stats, output_dir, &format!("{}.{}", base_name, name), gtag, config.rmdup)?;
+            input_names.push(name);
+        }
+    }
+    if config.histograms {
+        write_chrom_pair_table(&stats, &get_chrom_sizes(&headers), output_dir, base_name)?;
+        write_cis_distance_report(&stats.cis_distances, output_dir, base_name)?;
+        write_insert_size_report(&stats.insert_sizes, output_dir, base_name)?;
+    }
+    if config.json {
+        // MultiQC file first, so that the JSON report lists it among the outputs
+        write_multiqc_report(&stats, output_dir, base_name, config.rmdup)?;
+        let mut input_files = inputs.clone();
+        input_files.extend(config.mates.iter().map(|(_, r2_input)| r2_input.clone()));
+        input_files.extend(config.fragment_files.iter().cloned());
+        input_files.extend(config.reference.iter().map(|reference| reference.display().to_string()));
+        let run = RunInfo {
+            parameters: get_report_parameters(config),
+            inputs: input_files,
+            input_statistics: if input_stats.len() > 1 {
+                input_stats.iter()
+                    .map(|(input, stats)| (input.clone(), get_statistics_json(stats, config.rmdup, gtag.is_some())))
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            outputs: list_output_files(output_dir, base_name, start_time)?.iter()
+                .map(|path| path.display().to_string())
+                .chain([output_dir.join(format!("{}.stats.json", base_name)).display().to_string()])
+                .collect(),
+            runtime: start.elapsed(),
+        };
+        write_json_report(&stats, &run, output_dir, base_name, config.rmdup, gtag.is_some())?;
+    }
+
+    if config.verbose {
+        info!("## Processing complete!");
+        info!("## Total reads processed: {}", stats.reads_counter);
+        info!("## Valid interactions: {}", stats.valid_counter);
+    }
+
+    Ok(stats)
+}
@@ -0,0 +1,310 @@
+//! Classification statistics and the .RSstat report
+
+use crate::classify::{get_valid_orientation, ClassifiedPair, DumpReason, FilterReason, InteractionType, Orientation};
+use crate::dedup;
+use crate::quality::{MultimapEvidence, QualityReason};
+use crate::histogram::{Binning, Histogram, CIS_BINS_PER_DECADE, INSERT_SIZE_BIN, INSERT_SIZE_CLASSES};
+use crate::reads::{get_cis_distance, get_read_tag, is_intra_chrom};
+use noodles_bam as bam;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Cis distance (bp) below which intrachromosomal valid pairs are short range
+pub const SHORT_RANGE_CIS_DIST: u64 = 20000;
+
+/// Counters of the classification, reported in the .RSstat file
+#[derive(Debug, Default)]
+pub struct Statistics {
+    pub reads_counter: u64,
+    pub de_counter: u64,
+    pub re_counter: u64,
+    pub sc_counter: u64,
+    pub valid_counter: u64,
+    pub valid_counter_ff: u64,
+    pub valid_counter_rr: u64,
+    pub valid_counter_fr: u64,
+    pub valid_counter_rf: u64,
+    pub single_counter: u64,
+    pub dump_counter: u64,
+    pub filt_counter: u64,
+    pub lowq_counter: u64,
+    pub orphan_counter: u64,
+    pub chimeric_counter: u64,
+    pub duplicate_counter: u64,
+    // Cis/trans split of the (deduplicated) valid pairs
+    pub cis_counter: u64,
+    pub cis_short_counter: u64,
+    pub cis_long_counter: u64,
+    pub trans_counter: u64,
+    // Valid pairs per (reference id 1, reference id 2), ordered
+    pub chrom_pair_contacts: BTreeMap<(usize, usize), u64>,
+    // Filtered, dumped and low quality pairs per reason
+    pub filt_reasons: BTreeMap<FilterReason, u64>,
+    pub dump_reasons: BTreeMap<DumpReason, u64>,
+    pub lowq_reasons: BTreeMap<QualityReason, u64>,
+    // Pairs with a multi-mapped mate, whatever their class, per evidence (R1 first)
+    pub multimap_counter: u64,
+    pub multimap_evidence: BTreeMap<MultimapEvidence, u64>,
+    // Allele specific counters
+    pub g1g1_ascounter: u64,
+    pub g2g2_ascounter: u64,
+    pub g1u_ascounter: u64,
+    pub ug1_ascounter: u64,
+    pub g2u_ascounter: u64,
+    pub ug2_ascounter: u64,
+    pub g1g2_ascounter: u64,
+    pub g2g1_ascounter: u64,
+    pub uu_ascounter: u64,
+    pub cf_ascounter: u64,
+    // Valid pairs per ligation junction (enzymes of the two ligated ends)
+    pub ligation_junctions: BTreeMap<String, u64>,
+    // Cis-distance histograms of valid pairs per orientation, insert-size histograms per class
+    pub cis_distances: BTreeMap<Orientation, Histogram>,
+    pub insert_sizes: BTreeMap<InteractionType, Histogram>,
+}
+
+/// Add the counters of another run (e.g. another input file) to the statistics
+pub fn merge_statistics(stats: &mut Statistics, other: &Statistics) {
+    stats.reads_counter += other.reads_counter;
+    stats.de_counter += other.de_counter;
+    stats.re_counter += other.re_counter;
+    stats.sc_counter += other.sc_counter;
+    stats.valid_counter += other.valid_counter;
+    stats.valid_counter_ff += other.valid_counter_ff;
+    stats.valid_counter_rr += other.valid_counter_rr;
+    stats.valid_counter_fr += other.valid_counter_fr;
+    stats.valid_counter_rf += other.valid_counter_rf;
+    stats.single_counter += other.single_counter;
+    stats.dump_counter += other.dump_counter;
+    stats.filt_counter += other.filt_counter;
+    stats.lowq_counter += other.lowq_counter;
+    stats.orphan_counter += other.orphan_counter;
+    stats.chimeric_counter += other.chimeric_counter;
+    stats.duplicate_counter += other.duplicate_counter;
+    stats.cis_counter += other.cis_counter;
+    stats.cis_short_counter += other.cis_short_counter;
+    stats.cis_long_counter += other.cis_long_counter;
+    stats.trans_counter += other.trans_counter;
+    for (&chrom_pair, count) in &other.chrom_pair_contacts {
+        *stats.chrom_pair_contacts.entry(chrom_pair).or_default() += count;
+    }
+    for (&reason, count) in &other.filt_reasons {
+        *stats.filt_reasons.entry(reason).or_default() += count;
+    }
+    for (&reason, count) in &other.dump_reasons {
+        *stats.dump_reasons.entry(reason).or_default() += count;
+    }
+    for (&reason, count) in &other.lowq_reasons {
+        *stats.lowq_reasons.entry(reason).or_default() += count;
+    }
+    stats.multimap_counter += other.multimap_counter;
+    for (&evidence, count) in &other.multimap_evidence {
+        *stats.multimap_evidence.entry(evidence).or_default() += count;
+    }
+    stats.g1g1_ascounter += other.g1g1_ascounter;
+    stats.g2g2_ascounter += other.g2g2_ascounter;
+    stats.g1u_ascounter += other.g1u_ascounter;
+    stats.ug1_ascounter += other.ug1_ascounter;
+    stats.g2u_ascounter += other.g2u_ascounter;
+    stats.ug2_ascounter += other.ug2_ascounter;
+    stats.g1g2_ascounter += other.g1g2_ascounter;
+    stats.g2g1_ascounter += other.g2g1_ascounter;
+    stats.uu_ascounter += other.uu_ascounter;
+    stats.cf_ascounter += other.cf_ascounter;
+    for (junction, count) in &other.ligation_junctions {
+        *stats.ligation_junctions.entry(junction.clone()).or_default() += count;
+    }
+    for (&orientation, histogram) in &other.cis_distances {
+        stats.cis_distances.entry(orientation)
+            .or_insert_with(|| Histogram::new(histogram.binning))
+            .merge(histogram);
+    }
+    for (&class, histogram) in &other.insert_sizes {
+        stats.insert_sizes.entry(class)
+            .or_insert_with(|| Histogram::new(histogram.binning))
+            .merge(histogram);
+    }
+}
+
+/// Count an ordered valid pair in its allele-specific class, from the gtag values of both reads
+pub fn update_allele_statistics(stats: &mut Statistics, read1: &bam::Record, read2: &bam::Record, gtag: &str) {
+    let r1as = get_read_tag(read1, gtag);
+    let r2as = get_read_tag(read2, gtag);
+    match (r1as, r2as) {
+        (Some(1), Some(1)) => stats.g1g1_ascounter += 1,
+        (Some(2), Some(2)) => stats.g2g2_ascounter += 1,
+        (Some(1), Some(0)) => stats.g1u_ascounter += 1,
+        (Some(0), Some(1)) => stats.ug1_ascounter += 1,
+        (Some(2), Some(0)) => stats.g2u_ascounter += 1,
+        (Some(0), Some(2)) => stats.ug2_ascounter += 1,
+        (Some(1), Some(2)) => stats.g1g2_ascounter += 1,
+        (Some(2), Some(1)) => stats.g2g1_ascounter += 1,
+        (Some(3), _) | (_, Some(3)) => stats.cf_ascounter += 1,
+        _ => stats.uu_ascounter += 1,
+    }
+}
+
+/// Count a valid pair as cis (short or long range) or trans, and in its chromosome pair
+pub fn update_contact_statistics(stats: &mut Statistics, read1: &bam::Record, read2: &bam::Record) {
+    let tid1 = read1.reference_sequence_id().transpose().ok().flatten();
+    let tid2 = read2.reference_sequence_id().transpose().ok().flatten();
+    let Some((tid1, tid2)) = tid1.zip(tid2) else {
+        return;
+    };
+    if tid1 == tid2 {
+        stats.cis_counter += 1;
+        if get_cis_distance(read1, read2).is_some_and(|dist| (dist as u64) < SHORT_RANGE_CIS_DIST) {
+            stats.cis_short_counter += 1;
+        } else {
+            stats.cis_long_counter += 1;
+        }
+    } else {
+        stats.trans_counter += 1;
+    }
+    *stats.chrom_pair_contacts.entry((tid1.min(tid2), tid1.max(tid2))).or_default() += 1;
+}
+
+/// Count a pair with a multi-mapped mate, with the evidence of R1, or else of R2
+pub fn update_multimap_statistics(stats: &mut Statistics, pair: &ClassifiedPair) {
+    if let Some(evidence) = pair.r1_multimap.or(pair.r2_multimap) {
+        stats.multimap_counter += 1;
+        *stats.multimap_evidence.entry(evidence).or_default() += 1;
+    }
+}
+
+/// Add the insert size of a VI/DE/SC/RE pair and the cis distance of an intrachromosomal valid pair to their histograms
+pub fn update_distance_statistics(stats: &mut Statistics, pair: &ClassifiedPair) {
+    if let Some(dist) = pair.dist.filter(|_| INSERT_SIZE_CLASSES.contains(&pair.interaction_type)) {
+        stats.insert_sizes.entry(pair.interaction_type)
+            .or_insert_with(|| Histogram::new(Binning::Linear(INSERT_SIZE_BIN)))
+            .add(dist);
+    }
+    if pair.interaction_type == InteractionType::Valid
+        && is_intra_chrom(&pair.r1, &pair.r2) == Some(true)
+        && let (Some(orientation), Some(cis_dist)) = (get_valid_orientation(&pair.r1, &pair.r2), get_cis_distance(&pair.r1, &pair.r2)) {
+        stats.cis_distances.entry(orientation)
+            .or_insert_with(|| Histogram::new(Binning::Log(CIS_BINS_PER_DECADE)))
+            .add(cis_dist as u64);
+    }
+}
+
+/// Valid pairs without duplicates, duplication rate and estimated library complexity
+pub fn get_duplicate_summary(stats: &Statistics) -> (u64, f64, Option<u64>) {
+    let unique_pairs = stats.valid_counter - stats.duplicate_counter;
+    let duplication_rate = if stats.valid_counter > 0 {
+        stats.duplicate_counter as f64 / stats.valid_counter as f64
+    } else {
+        0.0
+    };
+    (unique_pairs, duplication_rate, dedup::estimate_library_size(stats.valid_counter, unique_pairs))
+}
+
+/// Write the <base>.RSstat report, with the duplicate and allele sections when enabled
+pub fn write_statistics(stats: &Statistics, output_dir: &PathBuf, base_name: &str, gtag: Option<&String>,
+    rmdup: bool) -> Result<(), Box<dyn Error>> {
+    let stat_file = output_dir.join(format!("{}.RSstat", base_name));
+    let mut stat_writer = BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(stat_file)?);
+    
+    writeln!(stat_writer, "## Hi-C processing")?;
+    writeln!(stat_writer, "Valid_interaction_pairs\t{}", stats.valid_counter)?;
+    writeln!(stat_writer, "Valid_interaction_pairs_FF\t{}", stats.valid_counter_ff)?;
+    writeln!(stat_writer, "Valid_interaction_pairs_RR\t{}", stats.valid_counter_rr)?;
+    writeln!(stat_writer, "Valid_interaction_pairs_RF\t{}", stats.valid_counter_rf)?;
+    writeln!(stat_writer, "Valid_interaction_pairs_FR\t{}", stats.valid_counter_fr)?;
+    writeln!(stat_writer, "Dangling_end_pairs\t{}", stats.de_counter)?;
+    writeln!(stat_writer, "Religation_pairs\t{}", stats.re_counter)?;
+    writeln!(stat_writer, "Self_Cycle_pairs\t{}", stats.sc_counter)?;
+    writeln!(stat_writer, "Single-end_pairs\t{}", stats.single_counter)?;
+    writeln!(stat_writer, "Filtered_pairs\t{}", stats.filt_counter)?;
+    writeln!(stat_writer, "Dumped_pairs\t{}", stats.dump_counter)?;
+    writeln!(stat_writer, "Low_quality_pairs\t{}", stats.lowq_counter)?;
+    writeln!(stat_writer, "Orphan_mates\t{}", stats.orphan_counter)?;
+    writeln!(stat_writer, "Chimeric_pairs\t{}", stats.chimeric_counter)?;
+
+    writeln!(stat_writer, "## ======================================")?;
+    writeln!(stat_writer, "## Filtered, dumped and low quality pairs per reason")?;
+    for reason in FilterReason::ALL {
+        writeln!(stat_writer, "Filtered_pairs_{}\t{}", reason, stats.filt_reasons.get(&reason).unwrap_or(&0))?;
+    }
+    for reason in DumpReason::ALL {
+        writeln!(stat_writer, "Dumped_pairs_{}\t{}", reason, stats.dump_reasons.get(&reason).unwrap_or(&0))?;
+    }
+    for reason in QualityReason::ALL {
+        writeln!(stat_writer, "Low_quality_pairs_{}\t{}", reason, stats.lowq_reasons.get(&reason).unwrap_or(&0))?;
+    }
+
+    writeln!(stat_writer, "## ======================================")?;
+    writeln!(stat_writer, "## Pairs with a multi-mapped mate")?;
+    writeln!(stat_writer, "Multimapped_pairs\t{}", stats.multimap_counter)?;
+    for evidence in MultimapEvidence::ALL {
+        writeln!(stat_writer, "Multimapped_pairs_{}\t{}", evidence, stats.multimap_evidence.get(&evidence).unwrap_or(&0))?;
+    }
+
+    writeln!(stat_writer, "## ======================================")?;
+    writeln!(stat_writer, "## Cis/trans valid pairs")?;
+    writeln!(stat_writer, "Cis_interaction_pairs\t{}", stats.cis_counter)?;
+    writeln!(stat_writer, "Cis_short_range_pairs_(<{}kb)\t{}", SHORT_RANGE_CIS_DIST / 1000, stats.cis_short_counter)?;
+    writeln!(stat_writer, "Cis_long_range_pairs_(>={}kb)\t{}", SHORT_RANGE_CIS_DIST / 1000, stats.cis_long_counter)?;
+    writeln!(stat_writer, "Trans_interaction_pairs\t{}", stats.trans_counter)?;
+
+    if !stats.ligation_junctions.is_empty() {
+        writeln!(stat_writer, "## ======================================")?;
+        writeln!(stat_writer, "## Ligation junctions of valid pairs")?;
+        for (junction, count) in &stats.ligation_junctions {
+            writeln!(stat_writer, "Ligation_junction_{}\t{}", junction, count)?;
+        }
+    }
+
+    if rmdup {
+        let (unique_pairs, duplication_rate, library_size) = get_duplicate_summary(stats);
+        let library_size = library_size
+            .map(|size| size.to_string())
+            .unwrap_or_else(|| "NA".to_string());
+        writeln!(stat_writer, "## ======================================")?;
+        writeln!(stat_writer, "## Duplicates of valid pairs")?;
+        writeln!(stat_writer, "Duplicate_pairs\t{}", stats.duplicate_counter)?;
+        writeln!(stat_writer, "Valid_interaction_pairs_rmdup\t{}", unique_pairs)?;
+        writeln!(stat_writer, "Duplication_rate\t{:.4}", duplication_rate)?;
+        writeln!(stat_writer, "Library_complexity\t{}", library_size)?;
+    }
+
+    if let Some(_gtag) = gtag {
+        writeln!(stat_writer, "## ======================================")?;
+        writeln!(stat_writer, "## Allele specific information")?;
+        writeln!(stat_writer, "Valid_pairs_from_ref_genome_(1-1)\t{}", stats.g1g1_ascounter)?;
+        writeln!(stat_writer, "Valid_pairs_from_ref_genome_with_one_unassigned_mate_(0-1/1-0)\t{}", 
+                 stats.ug1_ascounter + stats.g1u_ascounter)?;
+        writeln!(stat_writer, "Valid_pairs_from_alt_genome_(2-2)\t{}", stats.g2g2_ascounter)?;
+        writeln!(stat_writer, "Valid_pairs_from_alt_genome_with_one_unassigned_mate_(0-2/2-0)\t{}", 
+                 stats.ug2_ascounter + stats.g2u_ascounter)?;
+        writeln!(stat_writer, "Valid_pairs_from_alt_and_ref_genome_(1-2/2-1)\t{}", 
+                 stats.g1g2_ascounter + stats.g2g1_ascounter)?;
+        writeln!(stat_writer, "Valid_pairs_with_both_unassigned_mated_(0-0)\t{}", stats.uu_ascounter)?;
+        writeln!(stat_writer, "Valid_pairs_with_at_least_one_conflicting_mate_(3-)\t{}", stats.cf_ascounter)?;
+    }
+
+    Ok(())
+}
+
+/**
+    Write the <base>.chromPairs.tsv table, the number of valid pairs of each
+    chromosome pair, chromosomes being in the order of the BAM header
+
+    chrom_sizes : reference sequences of the BAM header
+ */
+pub fn write_chrom_pair_table(stats: &Statistics, chrom_sizes: &[(String, u64)], output_dir: &Path,
+    base_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("{}.chromPairs.tsv", base_name)))?);
+    writeln!(writer, "chrom1\tchrom2\tcount")?;
+    for (&(tid1, tid2), count) in &stats.chrom_pair_contacts {
+        let (Some((chrom1, _)), Some((chrom2, _))) = (chrom_sizes.get(tid1), chrom_sizes.get(tid2)) else {
+            continue;
+        };
+        writeln!(writer, "{}\t{}\t{}", chrom1, chrom2, count)?;
+    }
+    writer.flush()?;
+    Ok(())
+}